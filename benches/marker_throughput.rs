@@ -0,0 +1,16 @@
+//! how many detections per second the marker subscriber takes, fed with ten minutes
+//! of a 60 hz stream of the simulated markers, see `marker_throughput`. like every
+//! build it needs a sourced ros environment:
+//!
+//!     cargo bench --bench marker_throughput
+
+#[allow(dead_code)]
+#[path = "../src/main.rs"]
+mod estimator;
+
+fn main() {
+    let (detections, elapsed) = estimator::marker_throughput(36_000);
+    let elapsed = elapsed.as_secs_f64();
+    println!("{} detections in {:.3} s, {:.0} per second", detections, elapsed,
+             detections as f64 / elapsed);
+}
@@ -1,331 +1,3762 @@
-use r2r::geometry_msgs::msg::TransformStamped;
+use r2r::geometry_msgs::msg::{Point, Pose, Pose2D, PoseWithCovariance, PoseWithCovarianceStamped,
+                              TransformStamped, Twist, TwistStamped, TwistWithCovariance};
+use r2r::nav_msgs::msg::Odometry;
 use r2r::tf2_msgs::msg::TFMessage;
-use r2r::{Context, Node};
-use r2r::std_msgs::msg::Bool;
+use r2r::diagnostic_msgs::msg::{DiagnosticArray, DiagnosticStatus, KeyValue};
+use r2r::visualization_msgs::msg::{Marker, MarkerArray};
+use r2r::{Context, Node, ParameterValue, Publisher, WrappedTypesupport};
+use r2r::std_msgs::msg::{Bool, ColorRGBA, Float64};
 use r2r::std_srvs::srv::Trigger;
-use std::sync::{Arc, Mutex};
-//use std::time::Duration;
-use futures::stream::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use futures::stream::{self, StreamExt};
 use futures::future;
-use cgmath::{Deg, Rad, Euler, Quaternion, Vector3};
+use cgmath::{Deg, Rad, Euler, InnerSpace, Matrix4, Quaternion, Rotation3, Vector3, Vector4, Zero};
 
+// the state shared between the tasks is split into the raw markers, what is
+// derived from them and the locks, so that each task only locks what it uses.
+// tasks needing more than one lock them in that order, and the frames last.
+
+/// the raw markers, updated by every detection.
+#[derive(Default)]
+struct MarkerState {
+    // filtered markers by child frame id. two markers define the facade position
+    // (aruco_0 and aruco_1 by default), two the gantry position (aruco_2 and
+    // aruco_15 by default) and one marker is the agv (aruco_5 by default).
+    markers: HashMap<String, TransformStamped>,
+
+    // the latest raw detection of every marker by every input, keyed by input
+    // and marker id, see `observations_to_fuse`
+    observations: HashMap<(String, String), Observation>,
+
+    // filter state per marker id
+    marker_filters: HashMap<String, MarkerFilter>,
+
+    log_throttle: LogThrottle,
+
+    // marker events not published yet, see `marker_event`
+    marker_events: Vec<String>,
+
+    // detections per marker since the start of the current rate window
+    marker_counts: HashMap<String, usize>,
+
+    // arrival of the last message of any input, for the input watchdog
+    last_input: Option<Instant>,
+}
+
+impl MarkerState {
+    /// forget all markers, so that the filters start over from the next measurement.
+    fn reset(&mut self) {
+        *self = MarkerState {
+            log_throttle: std::mem::take(&mut self.log_throttle),
+            marker_events: std::mem::take(&mut self.marker_events),
+            last_input: self.last_input,
+            ..MarkerState::default()
+        };
+    }
+}
+
+/// what is derived from the markers and published.
 #[derive(Clone, Default)]
 struct State {
-    // markers 0 and 1 define the facade position
-    marker_0: Option<TransformStamped>,
-    marker_1: Option<TransformStamped>,
+    // computed results
+    facade_transform: Option<TransformStamped>,
+    gantry_transform: Option<TransformStamped>,
+    agv_transform: Option<TransformStamped>,
+
+    // offset from the remaining marker to the derived position while only one
+    // marker of the pair is left, see `update_pair_transform`
+    facade_single_offset: Option<Vector3<f64>>,
+    gantry_single_offset: Option<Vector3<f64>>,
+
+    log_throttle: LogThrottle,
+
+    // debounced value of the measured topic
+    measured: Debounce,
+
+    // gantry velocity from consecutive gantry transforms
+    gantry_velocity: VelocityEstimate,
+    agv_velocity: VelocityEstimate,
+
+    // recent facade and gantry transforms, for the quality of a lock
+    facade_history: TransformHistory,
+    gantry_history: TransformHistory,
+
+    // recent facade and gantry positions, for auto locking
+    facade_stability: Stability,
+    gantry_stability: Stability,
+
+    input_lost: bool,
+}
+
+impl State {
+    /// forget everything derived from the markers.
+    fn reset(&mut self) {
+        *self = State {
+            log_throttle: std::mem::take(&mut self.log_throttle),
+            input_lost: self.input_lost,
+            ..State::default()
+        };
+    }
+}
+
+/// the locked results.
+#[derive(Default)]
+struct Locks {
+    facade: Option<TransformStamped>,
+    gantry: Option<TransformStamped>,
+}
+
+/// configuration of a structure defined by a pair of markers.
+#[derive(Clone, Debug)]
+struct PairParams {
+    name: String,
+    child_frame_id: String,
+    // overrides the measured z when set
+    height: Option<f64>,
+    // place the frame between the markers instead of at the second marker
+    midpoint: bool,
+    // expected planar distance between the markers, 0 disables the check
+    spacing: f64,
+    spacing_tolerance: f64,
+    // drop the derived transform when the spacing is off, instead of keeping the last one
+    spacing_drop: bool,
+    // flip the yaw from the marker positions when it disagrees with the
+    // orientation the markers measure themselves
+    disambiguate_yaw: bool,
+    // how far to move the yaw towards the measured marker orientation, 0..1
+    marker_yaw_weight: f64,
+    // flip the yaw when it is more than 90° off the previous yaw, or the front
+    // direction while there is no previous one
+    yaw_continuity: bool,
+    front_yaw: Option<f64>,
+    // take the yaw from the second to the first marker, for pairs mounted the
+    // other way around
+    flip: bool,
+    // keep the derived transform alive from one marker when the other is gone
+    single_marker_fallback: bool,
+    // mounting offset applied in the coordinates of the derived frame, see `apply_offset`
+    offset: Option<TransformStamped>,
+}
+
+/// configuration of the marker subscriber, see `process_marker`.
+struct MarkerParams {
+    // the markers used for anything, all others are ignored
+    interested_in: Vec<String>,
+    min_range: f64,
+    max_range: f64,
+    // fuse the detections of a marker by several inputs, see `fuse_observations`
+    fuse: bool,
+    fusion_window_ns: i64,
+    target_frame: String,
+    filter: FilterParams,
+    facade_ids: [String; 2],
+    gantry_ids: [String; 2],
+    facade_extra_ids: Vec<String>,
+    gantry_extra_ids: Vec<String>,
+    facade_pair: PairParams,
+    gantry_pair: PairParams,
+    quality_window: usize,
+    agv_id: String,
+    agv_frame: String,
+}
+
+/// how quickly the confidence in a derived transform falls off. each term is
+/// mapped through exp(-value / scale), so at the scale it has dropped to 1/e.
+#[derive(Clone, Debug)]
+struct ConfidenceScales {
+    age_secs: f64,
+    position_jitter: f64,
+    yaw_jitter: f64,
+}
+
+/// how far the low-pass filter moves towards each new sample, as 1/factor of
+/// the difference. translation has a factor per axis.
+#[derive(Clone, Debug)]
+struct Smoothing {
+    translation: [f64; 3],
+    rotation: f64,
+}
+
+/// tuning of the per marker filtering.
+#[derive(Clone, Debug)]
+struct FilterParams {
+    smoothing: Smoothing,
+    // smoothing of single markers by child frame id, replacing `smoothing`
+    marker_smoothing: HashMap<String, Smoothing>,
+    // time constant of the low-pass filter in seconds. when set it replaces
+    // `smoothing`, so that the filter does not depend on the camera frame rate.
+    time_constant: Option<f64>,
+    // number of raw samples to take the median over, 1 disables the median filter
+    median_window: usize,
+    // samples further than this from the current estimate are rejected...
+    max_jump_meters: f64,
+    // ...unless this many consecutive samples agree on the new position
+    jump_accept_count: usize,
+    // markers seen too much from the side are rejected, see `marker_ok`
+    max_tilt_xy: f64,
+    min_up_z: f64,
+    // samples stamped further than this ahead of the node clock are rejected,
+    // 0 disables the check
+    max_future_skew_ns: i64,
+    // a marker whose raw pose stays within the epsilon (m and rad) for the timeout
+    // is reported as frozen, and rejected if `frozen_is_stale`. 0 disables the check
+    frozen_epsilon: f64,
+    frozen_timeout_ns: i64,
+    frozen_is_stale: bool,
+}
+
+// visualization_msgs/Marker types and actions
+const MARKER_CUBE: i32 = 1;
+const MARKER_TEXT_VIEW_FACING: i32 = 9;
+const MARKER_ADD: i32 = 0;
+const MARKER_DELETE: i32 = 2;
+
+// diagnostic_msgs/DiagnosticStatus levels
+const DIAGNOSTIC_OK: u8 = 0;
+const DIAGNOSTIC_WARN: u8 = 1;
+const DIAGNOSTIC_ERROR: u8 = 2;
+
+/// marker pairs closer than this (in the plane) do not define a yaw.
+const MIN_MARKER_DISTANCE: f64 = 0.01;
+
+/// number of recent raw samples kept per marker, for the jitter estimate.
+const HISTORY_LEN: usize = 10;
+
+/// per marker filter state that is kept between samples.
+#[derive(Clone, Default)]
+struct MarkerFilter {
+    // recent raw translations, used for the median pre-filter
+    history: VecDeque<Vector3<f64>>,
+    // number of consecutive rejected jumps and the position of the last one
+    jump_count: usize,
+    last_jump: Option<Vector3<f64>>,
+    // the raw pose the marker has kept since the stamp, to detect a stuck detector
+    frozen_pose: Option<(Vector3<f64>, Quaternion<f64>)>,
+    frozen_since_ns: i64,
+}
+
+/// keeps track of when a message was last logged per key, so that
+/// repeated warnings can be limited to once per period.
+#[derive(Clone, Default)]
+struct LogThrottle {
+    last_logged: HashMap<String, Instant>,
+}
+
+impl LogThrottle {
+    /// returns true if nothing has been logged for `key` during the last `period`.
+    fn ready(&mut self, key: &str, period: Duration) -> bool {
+        let now = Instant::now();
+        match self.last_logged.get(key) {
+            Some(last) if now.duration_since(*last) < period => false,
+            _ => {
+                self.last_logged.insert(key.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// a boolean that only follows its input after it has been stable for a while.
+#[derive(Clone, Default)]
+struct Debounce {
+    value: bool,
+    // when the input started to differ from `value`
+    changing_since: Option<i64>,
+}
+
+impl Debounce {
+    /// feed the current input, returns the debounced value. the input has to be
+    /// true for `on_ns` before the value turns true, and false for `off_ns`
+    /// before it turns false again.
+    fn update(&mut self, input: bool, now_ns: i64, on_ns: i64, off_ns: i64) -> bool {
+        if input == self.value {
+            self.changing_since = None;
+            return self.value;
+        }
+        let since = *self.changing_since.get_or_insert(now_ns);
+        let hold_ns = if input { on_ns } else { off_ns };
+        if now_ns - since >= hold_ns {
+            self.value = input;
+            self.changing_since = None;
+        }
+        self.value
+    }
+}
+
+/// velocity estimated by differencing consecutive transforms.
+#[derive(Clone, Default)]
+struct VelocityEstimate {
+    last: Option<TransformStamped>,
+    // low-pass filtered linear velocity and yaw rate
+    linear: Option<Vector3<f64>>,
+    yaw_rate: f64,
+}
+
+impl VelocityEstimate {
+    /// feed the current transform. samples with the same stamp as the previous one
+    /// are ignored, and the estimate restarts when the samples are more than
+    /// `max_dt_ns` apart. each new sample moves the estimate 1/smooth of the way.
+    fn update(&mut self, t: &TransformStamped, smooth: f64, max_dt_ns: i64) {
+        let last = match self.last.replace(t.clone()) {
+            Some(last) => last,
+            None => return,
+        };
+        let dt_ns = stamp_to_nanos(&t.header.stamp) - stamp_to_nanos(&last.header.stamp);
+        if dt_ns == 0 {
+            return;
+        }
+        if dt_ns < 0 || dt_ns > max_dt_ns {
+            self.linear = None;
+            return;
+        }
+
+        let dt = dt_ns as f64 / 1e9;
+        let linear = (vector_from_msg(&t.transform.translation) -
+                      vector_from_msg(&last.transform.translation)) / dt;
+        let dyaw = transform_yaw(t) - transform_yaw(&last);
+        let yaw_rate = wrap_angle(dyaw) / dt;
+        match self.linear {
+            Some(old) => {
+                self.linear = Some(old + (linear - old) / smooth);
+                self.yaw_rate += (yaw_rate - self.yaw_rate) / smooth;
+            }
+            None => {
+                self.linear = Some(linear);
+                self.yaw_rate = yaw_rate;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = VelocityEstimate::default();
+    }
+}
+
+/// recent positions of a derived transform, to tell when it has settled.
+#[derive(Clone, Default)]
+struct Stability {
+    samples: VecDeque<(i64, Vector3<f64>)>,
+}
+
+impl Stability {
+    /// add the current transform. returns true once the positions over the last
+    /// `duration_ns` all stay within an rms spread of `threshold` around their mean.
+    fn update(&mut self, t: Option<&TransformStamped>, now_ns: i64, duration_ns: i64,
+              threshold: f64) -> bool {
+        let t = match t {
+            Some(t) => t,
+            None => {
+                self.samples.clear();
+                return false;
+            }
+        };
+        self.samples.push_back((now_ns, vector_from_msg(&t.transform.translation)));
+        // keep one sample at least `duration_ns` old, to know the window is covered
+        while self.samples.len() > 1 && now_ns - self.samples[1].0 >= duration_ns {
+            self.samples.pop_front();
+        }
+        if now_ns - self.samples[0].0 < duration_ns {
+            return false;
+        }
+
+        let n = self.samples.len() as f64;
+        let mean = self.samples.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, (_, v)| acc + v) / n;
+        let spread = (self.samples.iter()
+            .map(|(_, v)| (v - mean).magnitude2())
+            .sum::<f64>() / n).sqrt();
+        spread < threshold
+    }
+}
+
+/// a detection of a marker by one of the inputs, and its weight when it is fused
+/// with the detections of the same marker by the other inputs.
+#[derive(Clone)]
+struct Observation {
+    transform: TransformStamped,
+    weight: f64,
+}
+
+/// the recent observations of the marker of `newest` by all inputs, i.e. within
+/// `window_ns` of it, expressed in `common_frame` to fuse them in. those that
+/// cannot be expressed in it are left out. an empty common frame keeps the
+/// observations in the frame of `newest` as they are, leaving out the others.
+/// `now_ns` and `max_age_ns` are passed on to `in_target_frame`.
+fn observations_to_fuse(observations: &HashMap<(String, String), Observation>,
+                        newest: &TransformStamped, window_ns: i64, common_frame: &str,
+                        frames: &KnownTransforms, now_ns: i64, max_age_ns: i64)
+                        -> Vec<Observation> {
+    let stamp_ns = stamp_to_nanos(&newest.header.stamp);
+    observations.iter()
+        .filter(|((_, id), o)| *id == newest.child_frame_id &&
+                (stamp_ns - stamp_to_nanos(&o.transform.header.stamp)).abs() <= window_ns)
+        .filter_map(|(_, o)| {
+            let transform = if common_frame.is_empty() {
+                Some(o.transform.clone()).filter(|t| t.header.frame_id == newest.header.frame_id)
+            } else {
+                in_target_frame(&o.transform, common_frame, frames, now_ns, max_age_ns)
+            };
+            transform.map(|transform| Observation { transform, weight: o.weight })
+        })
+        .collect()
+}
+
+/// the weighted mean of the observations, with the header of the newest one.
+/// the rotations are averaged on the same hemisphere as the first one.
+fn fuse_observations(observations: &[Observation]) -> Option<TransformStamped> {
+    let newest = observations.iter()
+        .max_by_key(|o| stamp_to_nanos(&o.transform.header.stamp))?;
+    let total: f64 = observations.iter().map(|o| o.weight).sum();
+    let first = quaternion_from_msg(&observations[0].transform.transform.rotation).normalize();
+    let mut position = Vector3::new(0.0, 0.0, 0.0);
+    let mut rotation = Quaternion::new(0.0, 0.0, 0.0, 0.0);
+    for o in observations {
+        position += vector_from_msg(&o.transform.transform.translation) * (o.weight / total);
+        let mut q = quaternion_from_msg(&o.transform.transform.rotation).normalize();
+        if first.dot(q) < 0.0 {
+            q = -q;
+        }
+        rotation += q * o.weight;
+    }
+
+    let mut fused = newest.transform.clone();
+    fused.transform.translation.x = position.x;
+    fused.transform.translation.y = position.y;
+    fused.transform.translation.z = position.z;
+    set_msg_quaternion(&mut fused.transform.rotation, rotation.normalize());
+    Some(fused)
+}
+
+/// the last few values of a derived transform, to tell how much it jitters.
+#[derive(Clone, Default)]
+struct TransformHistory {
+    samples: VecDeque<(Vector3<f64>, f64)>,
+}
+
+impl TransformHistory {
+    /// add the position and yaw of the current transform, keeping the last `len`.
+    fn update(&mut self, t: Option<&TransformStamped>, len: usize) {
+        match t {
+            Some(t) => {
+                self.samples.push_back((vector_from_msg(&t.transform.translation),
+                                        transform_yaw(t)));
+                while self.samples.len() > len {
+                    self.samples.pop_front();
+                }
+            }
+            None => self.samples.clear(),
+        }
+    }
+
+    /// rms spread of the positions (m) and the yaws (rad) around their means.
+    fn jitter(&self) -> (f64, f64) {
+        if self.samples.is_empty() {
+            return (0.0, 0.0);
+        }
+        let n = self.samples.len() as f64;
+        let mean = self.samples.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, (v, _)| acc + v) / n;
+        let (sin, cos) = self.samples.iter()
+            .fold((0.0, 0.0), |(sin, cos), (_, yaw)| (sin + yaw.sin(), cos + yaw.cos()));
+        let mean_yaw = sin.atan2(cos);
+        let position = (self.samples.iter()
+            .map(|(v, _)| (v - mean).magnitude2())
+            .sum::<f64>() / n).sqrt();
+        let yaw = (self.samples.iter()
+            .map(|(_, yaw)| wrap_angle(yaw - mean_yaw).powi(2))
+            .sum::<f64>() / n).sqrt();
+        (position, yaw)
+    }
+}
+
+/// filter a detection into `markers`. returns whether it was accepted, a rejected
+/// one leaves the marker as it was.
+#[allow(clippy::too_many_arguments)]
+fn update_or_set(mut new: TransformStamped, markers: &mut HashMap<String, TransformStamped>,
+                 filter: &mut MarkerFilter, params: &FilterParams, now_ns: i64,
+                 throttle: &mut LogThrottle, events: &mut Vec<String>, logger: &str) -> bool {
+    // samples from the future break the stale logic, and out of order ones would
+    // move the estimate backwards in time. both come from clock trouble between
+    // machines, or reordering on the way.
+    let stamp_ns = stamp_to_nanos(&new.header.stamp);
+    if params.max_future_skew_ns > 0 && stamp_ns - now_ns > params.max_future_skew_ns {
+        if throttle.ready(&format!("future_{}", new.child_frame_id), Duration::from_secs(1)) {
+            r2r::log_warn!(logger, "marker {} is stamped {:.3} s in the future, rejecting",
+                           new.child_frame_id, (stamp_ns - now_ns) as f64 / 1e9);
+        }
+        return false;
+    }
+    if let Some(old) = markers.get(&new.child_frame_id) {
+        if stamp_ns < stamp_to_nanos(&old.header.stamp) {
+            if throttle.ready(&format!("old_{}", new.child_frame_id), Duration::from_secs(1)) {
+                r2r::log_warn!(logger, "marker {} is older than the current sample, ignoring",
+                               new.child_frame_id);
+            }
+            return false;
+        }
+    }
+
+    // reject degenerate detections before they get into the filter state,
+    // and normalize the rotation of the rest.
+    let sample = vector_from_msg(&new.transform.translation);
+    let q = quaternion_from_msg(&new.transform.rotation);
+    let norm = q.magnitude();
+    let finite = sample.x.is_finite() && sample.y.is_finite() && sample.z.is_finite();
+    if !finite || !norm.is_finite() || norm < 1e-6 {
+        if throttle.ready(&format!("invalid_{}", new.child_frame_id), Duration::from_secs(1)) {
+            r2r::log_warn!(logger, "marker {} has an invalid transform, rejecting",
+                           new.child_frame_id);
+        }
+        return false;
+    }
+    set_msg_quaternion(&mut new.transform.rotation, q / norm);
+
+    // a stuck detector can keep republishing the same pose with fresh stamps,
+    // which the stale check does not catch. real detections always jitter a bit.
+    if params.frozen_timeout_ns > 0 {
+        let q = q / norm;
+        let unchanged = filter.frozen_pose
+            .map(|(p, fq)| (sample - p).magnitude() <= params.frozen_epsilon &&
+                 2.0 * fq.dot(q).abs().min(1.0).acos() <= params.frozen_epsilon)
+            .unwrap_or(false);
+        if !unchanged {
+            filter.frozen_pose = Some((sample, q));
+            filter.frozen_since_ns = stamp_ns;
+        } else if stamp_ns - filter.frozen_since_ns > params.frozen_timeout_ns {
+            if throttle.ready(&format!("frozen_{}", new.child_frame_id), Duration::from_secs(5)) {
+                r2r::log_warn!(logger, "marker {} has not moved for {:.1} s although its stamps \
+                                        advance, the detector may be stuck", new.child_frame_id,
+                               (stamp_ns - filter.frozen_since_ns) as f64 / 1e9);
+            }
+            if params.frozen_is_stale {
+                return false;
+            }
+        }
+    }
+
+    if !marker_ok(&new, params.max_tilt_xy, params.min_up_z) {
+        if throttle.ready(&format!("tilt_{}", new.child_frame_id), Duration::from_secs(1)) {
+            r2r::log_debug!(logger, "marker {} rejected (too tilted)", new.child_frame_id);
+        }
+        return false;
+    }
+
+    // reject samples jumping too far from the current estimate. if enough
+    // consecutive samples agree with each other the marker has actually moved.
+    let mut relocated = false;
+    if let Some(old) = markers.get(&new.child_frame_id) {
+        let jump = (sample - vector_from_msg(&old.transform.translation)).magnitude();
+        if jump > params.max_jump_meters {
+            let consistent = filter.last_jump
+                .map(|last| (sample - last).magnitude() <= params.max_jump_meters)
+                .unwrap_or(false);
+            filter.jump_count = if consistent { filter.jump_count + 1 } else { 1 };
+            filter.last_jump = Some(sample);
+            if filter.jump_count < params.jump_accept_count {
+                if throttle.ready(&format!("jump_{}", new.child_frame_id), Duration::from_secs(1)) {
+                    r2r::log_warn!(logger, "marker {} jumped {:.3} m, rejecting",
+                                   new.child_frame_id, jump);
+                }
+                return false;
+            }
+            r2r::log_info!(logger, "marker {} relocated by {:.3} m", new.child_frame_id, jump);
+            filter.history.clear();
+            relocated = true;
+        }
+    }
+    filter.jump_count = 0;
+    filter.last_jump = None;
+
+    filter.history.push_back(sample);
+    while filter.history.len() > HISTORY_LEN.max(params.median_window) {
+        filter.history.pop_front();
+    }
+
+    if params.median_window > 1 {
+        let median = median_translation(&filter.history, params.median_window);
+        let t = &mut new.transform.translation;
+        t.x = median.x;
+        t.y = median.y;
+        t.z = median.z;
+    }
+
+    let new = match markers.get(&new.child_frame_id) {
+        Some(old) if !relocated => {
+            let smoothing = match params.time_constant {
+                Some(tau) => {
+                    let dt_ns = stamp_to_nanos(&new.header.stamp) -
+                        stamp_to_nanos(&old.header.stamp);
+                    time_smoothing(tau, dt_ns as f64 / 1e9)
+                }
+                None => params.marker_smoothing.get(&new.child_frame_id)
+                    .unwrap_or(&params.smoothing).clone(),
+            };
+            filter_transform(new, old, &smoothing)
+        }
+        Some(_) => new,
+        None => {
+            marker_event(events, &new.child_frame_id, "live",
+                         stamp_to_nanos(&new.header.stamp), logger);
+            new
+        }
+    };
+    markers.insert(new.child_frame_id.clone(), new);
+    true
+}
+
+/// log a marker going live or stale, and queue the event for the marker_events topic.
+fn marker_event(events: &mut Vec<String>, id: &str, event: &str, stamp_ns: i64, logger: &str) {
+    if event == "live" {
+        r2r::log_info!(logger, "marker {} is {}", id, event);
+    } else {
+        r2r::log_warn!(logger, "marker {} is {}", id, event);
+    }
+    let json = serde_json::json!({ "marker": id, "event": event, "stamp": stamp_ns as f64 / 1e9 });
+    events.push(json.to_string());
+}
+
+fn vector_from_msg(v: &r2r::geometry_msgs::msg::Vector3) -> Vector3<f64> {
+    Vector3::new(v.x, v.y, v.z)
+}
+
+/// the per axis median of the last `window` samples in the history.
+fn median_translation(history: &VecDeque<Vector3<f64>>, window: usize) -> Vector3<f64> {
+    let skip = history.len().saturating_sub(window);
+    let median = |axis: fn(&Vector3<f64>) -> f64| {
+        let mut values: Vec<f64> = history.iter().skip(skip).map(axis).collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let mid = values.len() / 2;
+        if values.len().is_multiple_of(2) {
+            (values[mid - 1] + values[mid]) / 2.0
+        } else {
+            values[mid]
+        }
+    };
+
+    Vector3::new(median(|v| v.x), median(|v| v.y), median(|v| v.z))
+}
+
+/// the per axis variance of the samples in the history.
+fn translation_variance(history: &VecDeque<Vector3<f64>>) -> Vector3<f64> {
+    if history.len() < 2 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    let n = history.len() as f64;
+    let mean = history.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, v| acc + v) / n;
+    history.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, v| {
+        let d = v - mean;
+        acc + Vector3::new(d.x * d.x, d.y * d.y, d.z * d.z)
+    }) / n
+}
+
+/// build a pose with covariance from a derived transform. the variance is the
+/// base variance, grown linearly with the age of the measurement, plus the recent
+/// jitter of the markers it was computed from for the position and the variance
+/// of the recent yaws for the rotation about z.
+fn pose_with_covariance(t: &TransformStamped, jitter: Vector3<f64>, yaw_variance: f64,
+                        age_secs: f64, base_variance: f64, variance_per_sec: f64)
+                        -> PoseWithCovarianceStamped {
+    // row major 6x6 matrix over (x, y, z, rot x, rot y, rot z)
+    let variance = base_variance + variance_per_sec * age_secs.max(0.0);
+    let mut covariance = vec![0.0; 36];
+    covariance[0] = variance + jitter.x;
+    covariance[7] = variance + jitter.y;
+    covariance[14] = variance + jitter.z;
+    covariance[21] = variance;
+    covariance[28] = variance;
+    covariance[35] = variance + yaw_variance;
+
+    PoseWithCovarianceStamped {
+        header: t.header.clone(),
+        pose: PoseWithCovariance {
+            pose: pose_from_transform(t),
+            covariance,
+        },
+    }
+}
+
+/// the agv odometry, with the pose from `t` and the twist from the velocity in the
+/// parent frame, rotated into the agv frame.
+fn odometry(t: &TransformStamped, pose: PoseWithCovariance, linear: Vector3<f64>,
+            yaw_rate: f64, twist_variance: f64) -> Odometry {
+    let q = quaternion_from_msg(&t.transform.rotation).normalize();
+    let linear = q.conjugate() * linear;
+    let mut twist = Twist::default();
+    twist.linear.x = linear.x;
+    twist.linear.y = linear.y;
+    twist.linear.z = linear.z;
+    twist.angular.z = yaw_rate;
+
+    let mut covariance = vec![0.0; 36];
+    for i in 0..6 {
+        covariance[i * 7] = twist_variance;
+    }
+
+    Odometry {
+        header: t.header.clone(),
+        child_frame_id: t.child_frame_id.clone(),
+        pose,
+        twist: TwistWithCovariance { twist, covariance },
+    }
+}
+
+fn pose_from_transform(t: &TransformStamped) -> Pose {
+    Pose {
+        position: Point {
+            x: t.transform.translation.x,
+            y: t.transform.translation.y,
+            z: t.transform.translation.z,
+        },
+        orientation: t.transform.rotation.clone(),
+    }
+}
+
+/// rviz markers for one estimate, a cube and a text label in the namespace `ns`.
+/// when the estimate is missing the markers are deleted instead.
+fn visualization_markers(ns: &str, t: Option<&TransformStamped>, color: ColorRGBA) -> Vec<Marker> {
+    let t = match t {
+        Some(t) => t,
+        None => {
+            return (0..2).map(|id| Marker {
+                ns: ns.into(),
+                id,
+                action: MARKER_DELETE,
+                ..Default::default()
+            }).collect();
+        }
+    };
+
+    let cube = Marker {
+        header: t.header.clone(),
+        ns: ns.into(),
+        id: 0,
+        type_: MARKER_CUBE,
+        action: MARKER_ADD,
+        pose: pose_from_transform(t),
+        scale: r2r::geometry_msgs::msg::Vector3 { x: 0.3, y: 0.3, z: 0.05 },
+        color,
+        ..Default::default()
+    };
+    let label = Marker {
+        header: t.header.clone(),
+        ns: ns.into(),
+        id: 1,
+        type_: MARKER_TEXT_VIEW_FACING,
+        action: MARKER_ADD,
+        pose: pose_from_transform(t),
+        scale: r2r::geometry_msgs::msg::Vector3 { x: 0.0, y: 0.0, z: 0.1 },
+        color: ColorRGBA { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+        text: t.child_frame_id.clone(),
+        ..Default::default()
+    };
+    vec![cube, label]
+}
+
+/// distance between two markers in the plane.
+fn planar_distance(first: &TransformStamped, second: &TransformStamped) -> f64 {
+    let diff_x = second.transform.translation.x - first.transform.translation.x;
+    let diff_y = second.transform.translation.y - first.transform.translation.y;
+    diff_x.hypot(diff_y)
+}
+
+/// planar direction from the first to the second marker, or None when the
+/// markers are too close together for the direction to mean anything.
+fn pair_yaw(first: &TransformStamped, second: &TransformStamped) -> Option<f64> {
+    if planar_distance(first, second) < MIN_MARKER_DISTANCE {
+        return None;
+    }
+    let diff_x = second.transform.translation.x - first.transform.translation.x;
+    let diff_y = second.transform.translation.y - first.transform.translation.y;
+    Some(diff_y.atan2(diff_x))
+}
+
+/// the direction of the total least squares line through the marker positions
+/// in the plane, pointing the same way as `reference`.
+fn line_yaw(markers: &[&TransformStamped], reference: f64) -> f64 {
+    let n = markers.len() as f64;
+    let (sum_x, sum_y) = markers.iter().fold((0.0, 0.0), |(x, y), m| {
+        (x + m.transform.translation.x, y + m.transform.translation.y)
+    });
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (sxx, syy, sxy) = markers.iter().fold((0.0, 0.0, 0.0), |(sxx, syy, sxy), m| {
+        let dx = m.transform.translation.x - mean_x;
+        let dy = m.transform.translation.y - mean_y;
+        (sxx + dx * dx, syy + dy * dy, sxy + dx * dy)
+    });
+    let yaw = 0.5 * (2.0 * sxy).atan2(sxx - syy);
+    if wrap_angle(yaw - reference).abs() > std::f64::consts::FRAC_PI_2 {
+        wrap_angle(yaw + std::f64::consts::PI)
+    } else {
+        yaw
+    }
+}
+
+/// the configured extra markers of a structure that are currently present.
+fn extra_markers<'a>(markers: &'a HashMap<String, TransformStamped>, ids: &[String])
+                     -> Vec<&'a TransformStamped> {
+    ids.iter().filter_map(|id| markers.get(id)).collect()
+}
+
+/// look up the markers of a pair by their configured ids.
+fn pair_markers<'a>(markers: &'a HashMap<String, TransformStamped>, ids: &[String; 2])
+                    -> [Option<&'a TransformStamped>; 2] {
+    [markers.get(&ids[0]), markers.get(&ids[1])]
+}
+
+/// recompute the derived transform of a structure from its pair of markers, and
+/// the `extra` markers along the same line if there are any. the transform is
+/// dropped or left untouched when the marker spacing is off, see `spacing_drop`.
+fn update_pair_transform(markers: [Option<&TransformStamped>; 2],
+                         extra: &[&TransformStamped],
+                         derived: &mut Option<TransformStamped>,
+                         single_offset: &mut Option<Vector3<f64>>, params: &PairParams,
+                         throttle: &mut LogThrottle, logger: &str) {
+    let (first, second) = match markers {
+        [Some(first), Some(second)] => (first, second),
+        [Some(marker), None] | [None, Some(marker)] if params.single_marker_fallback => {
+            update_single_marker(marker, derived, single_offset, params, logger);
+            return;
+        }
+        _ => {
+            *derived = None;
+            *single_offset = None;
+            return;
+        }
+    };
+    if single_offset.take().is_some() {
+        r2r::log_info!(logger, "both {} markers are back", params.name);
+    }
+
+    if params.spacing > 0.0 {
+        let spacing = planar_distance(first, second);
+        if (spacing - params.spacing).abs() > params.spacing_tolerance {
+            let action = if params.spacing_drop { "dropping it" } else { "not updating" };
+            if throttle.ready(&format!("spacing_{}", params.name), Duration::from_secs(1)) {
+                r2r::log_warn!(logger, "{} marker spacing is {:.3} m, expected {:.3} m, {}",
+                               params.name, spacing, params.spacing, action);
+            }
+            if params.spacing_drop {
+                *derived = None;
+            }
+            return;
+        }
+    }
+
+    // keep the previous yaw if the markers are on top of each other
+    let position_yaw = if params.flip {
+        pair_yaw(second, first)
+    } else {
+        pair_yaw(first, second)
+    };
+    // with more markers the yaw is that of the line fitted through all of them,
+    // the end markers only tell which way it points.
+    let position_yaw = match position_yaw {
+        Some(yaw) if !extra.is_empty() => {
+            let mut all = vec![first, second];
+            all.extend_from_slice(extra);
+            Some(line_yaw(&all, yaw))
+        }
+        yaw => yaw,
+    };
+    // the yaw of the previous transform, before its mounting offset
+    let previous_yaw = derived.as_ref().map(|t| match params.offset.as_ref() {
+        Some(offset) => transform_yaw(&apply_offset(t, &invert(offset))),
+        None => transform_yaw(t),
+    });
+    let yaw = match position_yaw {
+        Some(yaw) => {
+            let measured = circular_mean(transform_yaw(first), transform_yaw(second));
+            let yaw = blend_yaw(yaw, measured, params.disambiguate_yaw, params.marker_yaw_weight);
+            let reference = previous_yaw.filter(|_| params.yaw_continuity).or(params.front_yaw);
+            match reference {
+                Some(reference) if wrap_angle(yaw - reference).abs() >
+                    std::f64::consts::FRAC_PI_2 => {
+                    if throttle.ready(&format!("flip_{}", params.name), Duration::from_secs(1)) {
+                        r2r::log_info!(logger, "{} yaw flipped by 180°, turning it back",
+                                       params.name);
+                    }
+                    Some(wrap_angle(yaw + std::f64::consts::PI))
+                }
+                _ => Some(yaw),
+            }
+        }
+        None => {
+            if throttle.ready(&format!("degenerate_{}", params.name), Duration::from_secs(1)) {
+                r2r::log_warn!(logger, "{} markers coincide, keeping previous yaw", params.name);
+            }
+            previous_yaw
+        }
+    };
+
+    *derived = yaw.map(|yaw| {
+        let mut t = pair_transform(first, second, &params.child_frame_id, params.midpoint, yaw);
+        if let Some(height) = params.height {
+            t.transform.translation.z = height;
+        }
+        match params.offset.as_ref() {
+            Some(offset) => apply_offset(&t, offset),
+            None => t,
+        }
+    });
+}
+
+/// degraded update of a pair with only one marker left. the derived transform
+/// follows that marker with the offset it had when the other one was lost, and
+/// keeps its last yaw. without a previous transform there is no yaw to keep, so
+/// nothing is derived.
+fn update_single_marker(marker: &TransformStamped, derived: &mut Option<TransformStamped>,
+                        single_offset: &mut Option<Vector3<f64>>, params: &PairParams,
+                        logger: &str) {
+    let last = match derived.as_ref() {
+        Some(last) => last,
+        None => return,
+    };
+    let position = vector_from_msg(&marker.transform.translation);
+    let offset = *single_offset.get_or_insert_with(|| {
+        r2r::log_warn!(logger, "only one {} marker left, holding the yaw with reduced \
+                                confidence", params.name);
+        vector_from_msg(&last.transform.translation) - position
+    });
+
+    let mut t = last.clone();
+    t.header = marker.header.clone();
+    let p = position + offset;
+    t.transform.translation.x = p.x;
+    t.transform.translation.y = p.y;
+    // with a height the z stays that of the last transform, i.e. the height with
+    // the mounting offset applied like for the pair.
+    if params.height.is_none() {
+        t.transform.translation.z = p.z;
+    }
+    *derived = Some(t);
+}
+
+/// combine the yaw from the marker positions with the yaw measured by the markers
+/// themselves. when disambiguating, the position yaw is flipped by 180° if it is
+/// more than 90° off the measured one. it is then moved `weight` of the way towards it.
+fn blend_yaw(position_yaw: f64, measured_yaw: f64, disambiguate: bool, weight: f64) -> f64 {
+    let mut yaw = position_yaw;
+    if disambiguate && wrap_angle(measured_yaw - yaw).abs() > std::f64::consts::FRAC_PI_2 {
+        yaw += std::f64::consts::PI;
+    }
+    wrap_angle(yaw + weight * wrap_angle(measured_yaw - yaw))
+}
+
+/// the mean direction of two angles.
+fn circular_mean(a: f64, b: f64) -> f64 {
+    (a.sin() + b.sin()).atan2(a.cos() + b.cos())
+}
+
+/// wrap an angle into [-pi, pi].
+fn wrap_angle(a: f64) -> f64 {
+    a.sin().atan2(a.cos())
+}
+
+/// the yaw of a derived transform, i.e. the heading of its x axis in the plane.
+fn transform_yaw(t: &TransformStamped) -> f64 {
+    let x = quaternion_from_msg(&t.transform.rotation) * Vector3::unit_x();
+    x.y.atan2(x.x)
+}
+
+/// derive the transform of a structure defined by a pair of markers. the yaw
+/// is given by `pair_yaw`, and the frame is turned upside down. the position
+/// is the second marker, or the midpoint between the two.
+fn pair_transform(first: &TransformStamped, second: &TransformStamped, child_frame_id: &str,
+                  midpoint: bool, yaw: f64) -> TransformStamped {
+    let mut new_transform = second.clone();
+    new_transform.child_frame_id = child_frame_id.into();
+
+    if midpoint {
+        let a = &first.transform.translation;
+        let t = &mut new_transform.transform.translation;
+        t.x = (a.x + t.x) / 2.0;
+        t.y = (a.y + t.y) / 2.0;
+        t.z = (a.z + t.z) / 2.0;
+    }
+
+    let rot = Quaternion::from(Euler {
+        x: Rad(0.0),
+        y: Rad(0.0),
+        z: Rad(yaw),
+    });
+
+    let rot2 = Quaternion::from(Euler {
+        x: Deg(180.0),
+        y: Deg(0.0),
+        z: Deg(0.0),
+    });
+
+    // set yaw and rotate around x to turn upside down. the flip around x is
+    // applied first, so a pair with `flip` set gets the same upside down frame
+    // turned 180° around its own z axis, i.e. with x and y reversed.
+    set_msg_quaternion(&mut new_transform.transform.rotation, rot * rot2);
+
+    new_transform
+}
+
+/// diagnostic status of a tracked marker. error when it is missing or stale,
+/// warn when it is older than half the stale timeout and ok otherwise.
+fn marker_diagnostic(id: &str, marker: Option<&TransformStamped>, now_ns: i64,
+                     stale_timeout_ns: i64) -> DiagnosticStatus {
+    let age_ns = marker.map(|t| now_ns - stamp_to_nanos(&t.header.stamp));
+    let (level, message) = match age_ns {
+        None => (DIAGNOSTIC_ERROR, "missing"),
+        Some(age_ns) if age_ns > stale_timeout_ns => (DIAGNOSTIC_ERROR, "stale"),
+        Some(age_ns) if age_ns > stale_timeout_ns / 2 => (DIAGNOSTIC_WARN, "old"),
+        Some(_) => (DIAGNOSTIC_OK, "live"),
+    };
+    let age = age_ns.map(|ns| format!("{:.3}", ns as f64 / 1e9)).unwrap_or_default();
+    DiagnosticStatus {
+        level,
+        name: format!("gantry_position_estimator: marker {}", id),
+        message: message.into(),
+        hardware_id: id.into(),
+        values: vec![
+            KeyValue { key: "present".into(), value: marker.is_some().to_string() },
+            KeyValue { key: "age_secs".into(), value: age },
+        ],
+    }
+}
+
+/// average jitter of the given markers.
+fn markers_jitter(filters: &HashMap<String, MarkerFilter>, ids: &[String]) -> Vector3<f64> {
+    let jitters: Vec<Vector3<f64>> = ids.iter()
+        .filter_map(|id| filters.get(id))
+        .map(|f| translation_variance(&f.history))
+        .collect();
+    if jitters.is_empty() {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    jitters.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, v| acc + v) / jitters.len() as f64
+}
+
+/// the smoothing of a low-pass filter with time constant `tau` for a sample `dt`
+/// seconds after the previous one, moving `dt / (tau + dt)` of the way. samples
+/// that are not newer than the previous one do not move the estimate.
+fn time_smoothing(tau: f64, dt: f64) -> Smoothing {
+    let factor = if dt > 0.0 { (tau.max(0.0) + dt) / dt } else { f64::INFINITY };
+    Smoothing { translation: [factor; 3], rotation: factor }
+}
+
+/// apply a low-pass filter to the position and rotation in the camera frame on incoming data.
+/// each new sample moves the estimate 1/smooth of the way towards it.
+fn filter_transform(mut new: TransformStamped, old: &TransformStamped,
+                    smooth: &Smoothing) -> TransformStamped {
+    let nx = new.transform.translation.x;
+    let ny = new.transform.translation.y;
+    let nz = new.transform.translation.z;
+
+    let ox = old.transform.translation.x;
+    let oy = old.transform.translation.y;
+    let oz = old.transform.translation.z;
+
+    let diff_x = (nx - ox) / smooth.translation[0];
+    let diff_y = (ny - oy) / smooth.translation[1];
+    let diff_z = (nz - oz) / smooth.translation[2];
+
+    new.transform.translation.x = ox + diff_x;
+    new.transform.translation.y = oy + diff_y;
+    new.transform.translation.z = oz + diff_z;
+
+    // slerp the rotation towards the new measurement, taking the shorter arc.
+    let oq = quaternion_from_msg(&old.transform.rotation).normalize();
+    let mut nq = quaternion_from_msg(&new.transform.rotation).normalize();
+    if oq.dot(nq) < 0.0 {
+        nq = -nq;
+    }
+    let q = oq.slerp(nq, 1.0 / smooth.rotation).normalize();
+    set_msg_quaternion(&mut new.transform.rotation, q);
+
+    new
+}
+
+/// compose the transform from frame a to frame b with the one from b to c,
+/// giving the transform from a to c with the header stamp of the second.
+fn compose(ab: &TransformStamped, bc: &TransformStamped) -> TransformStamped {
+    let q_ab = quaternion_from_msg(&ab.transform.rotation).normalize();
+    let q_bc = quaternion_from_msg(&bc.transform.rotation).normalize();
+    let p = q_ab * vector_from_msg(&bc.transform.translation) +
+        vector_from_msg(&ab.transform.translation);
+
+    let mut ac = bc.clone();
+    ac.header.frame_id = ab.header.frame_id.clone();
+    ac.transform.translation.x = p.x;
+    ac.transform.translation.y = p.y;
+    ac.transform.translation.z = p.z;
+    set_msg_quaternion(&mut ac.transform.rotation, (q_ab * q_bc).normalize());
+    ac
+}
+
+/// the inverse of a transform, with the parent and child frames swapped.
+fn invert(t: &TransformStamped) -> TransformStamped {
+    let q = quaternion_from_msg(&t.transform.rotation).normalize().conjugate();
+    let p = -(q * vector_from_msg(&t.transform.translation));
+
+    let mut inv = t.clone();
+    inv.header.frame_id = t.child_frame_id.clone();
+    inv.child_frame_id = t.header.frame_id.clone();
+    inv.transform.translation.x = p.x;
+    inv.transform.translation.y = p.y;
+    inv.transform.translation.z = p.z;
+    set_msg_quaternion(&mut inv.transform.rotation, q);
+    inv
+}
+
+/// move `t` by `offset`, given in the coordinates of `t` itself, keeping the
+/// frames and stamp of `t`. the derived frames are upside down, so a positive
+/// offset z moves them down.
+fn apply_offset(t: &TransformStamped, offset: &TransformStamped) -> TransformStamped {
+    let mut moved = compose(t, offset);
+    moved.header = t.header.clone();
+    moved.child_frame_id = t.child_frame_id.clone();
+    moved
+}
+
+/// a transform between two frames from /tf or /tf_static, or given as a parameter.
+struct KnownTransform {
+    transform: TransformStamped,
+    // static transforms never expire, the others only hold while they are fresh
+    is_static: bool,
+}
+
+/// the known transforms, keyed by their parent and child frame.
+type KnownTransforms = HashMap<(String, String), KnownTransform>;
+
+/// the transform from `target_frame` to `frame`, chained from the known transforms
+/// in either direction, e.g. map -> base -> camera. transforms that are not static
+/// are left out when they are more than `max_age_ns` older than `now_ns`.
+fn lookup_transform(target_frame: &str, frame: &str, frames: &KnownTransforms,
+                    now_ns: i64, max_age_ns: i64) -> Option<TransformStamped> {
+    let mut identity = TransformStamped::default();
+    identity.header.frame_id = target_frame.to_string();
+    identity.child_frame_id = target_frame.to_string();
+    identity.transform.rotation.w = 1.0;
+
+    // breadth first from the target frame, the transforms to all frames reached so far
+    let mut reached = HashMap::new();
+    reached.insert(target_frame.to_string(), identity);
+    let mut queue = VecDeque::new();
+    queue.push_back(target_frame.to_string());
+    while let Some(current) = queue.pop_front() {
+        if current == frame {
+            return reached.remove(frame);
+        }
+        let to_current = reached[&current].clone();
+        for ((parent, child), known) in frames {
+            if !known.is_static && is_stale(Some(&known.transform), now_ns, max_age_ns) {
+                continue;
+            }
+            let step = if *parent == current {
+                known.transform.clone()
+            } else if *child == current {
+                invert(&known.transform)
+            } else {
+                continue;
+            };
+            if !reached.contains_key(&step.child_frame_id) {
+                queue.push_back(step.child_frame_id.clone());
+                reached.insert(step.child_frame_id.clone(), compose(&to_current, &step));
+            }
+        }
+    }
+    None
+}
+
+/// express `t` in `target_frame`, given the known transforms, see `lookup_transform`.
+/// returns None if the transform from the target frame to the parent frame of `t`
+/// is not known. an empty target frame leaves `t` as it is.
+fn in_target_frame(t: &TransformStamped, target_frame: &str, frames: &KnownTransforms,
+                   now_ns: i64, max_age_ns: i64) -> Option<TransformStamped> {
+    if target_frame.is_empty() || t.header.frame_id == target_frame {
+        return Some(t.clone());
+    }
+    lookup_transform(target_frame, &t.header.frame_id, frames, now_ns, max_age_ns)
+        .map(|parent| compose(&parent, t))
+}
+
+fn quaternion_from_msg(q: &r2r::geometry_msgs::msg::Quaternion) -> Quaternion<f64> {
+    Quaternion::new(q.w, q.x, q.y, q.z)
+}
+
+fn set_msg_quaternion(q: &mut r2r::geometry_msgs::msg::Quaternion, new_q: Quaternion<f64>) {
+    q.w = new_q.s;
+    q.x = new_q.v.x;
+    q.y = new_q.v.y;
+    q.z = new_q.v.z;
+}
+
+/// filter out bad measurements, i.e. markers seen too much from the side.
+fn marker_ok(t: &TransformStamped, max_tilt_xy: f64, min_up_z: f64) -> bool {
+    // rotate the up vector of the marker into the camera frame
+    let up = Vector3::unit_z();
+    let q0 = Quaternion::new(t.transform.rotation.w, t.transform.rotation.x,
+                             t.transform.rotation.y, t.transform.rotation.z);
+    let rotated =  q0 * up;
+    rotated.x.abs() < max_tilt_xy && rotated.y.abs() < max_tilt_xy && rotated.z.abs() > min_up_z
+}
+
+/// the current ros time. follows the time published on /clock when sim time is
+/// used, and the ros clock otherwise.
+struct TimeSource {
+    clock: r2r::Clock,
+    sim_time: Option<Arc<AtomicI64>>,
+    // the last time read, in case the clock fails
+    last: Duration,
+}
+
+impl TimeSource {
+    fn create(sim_time: &Option<Arc<AtomicI64>>) -> r2r::Result<TimeSource> {
+        Ok(TimeSource {
+            clock: r2r::Clock::create(r2r::ClockType::RosTime)?,
+            sim_time: sim_time.clone(),
+            last: Duration::ZERO,
+        })
+    }
+
+    /// the current time. should the clock fail, the error is logged and the last
+    /// time read is returned instead of taking the task down.
+    fn now(&mut self, logger: &str) -> Duration {
+        let now = match self.sim_time.as_ref() {
+            Some(sim_time) => {
+                Ok(Duration::from_nanos(sim_time.load(Ordering::SeqCst).max(0) as u64))
+            }
+            None => self.clock.get_now(),
+        };
+        match now {
+            Ok(now) => self.last = now,
+            Err(e) => r2r::log_error!(logger, "could not get ros time: {}", e),
+        }
+        self.last
+    }
+}
+
+/// convert a ros time stamp into absolute nanoseconds.
+fn stamp_to_nanos(t: &r2r::builtin_interfaces::msg::Time) -> i64 {
+    t.sec as i64 * 1_000_000_000 + t.nanosec as i64
+}
+
+/// true if the transform exists and is older than `timeout_ns` relative to `now_ns`.
+fn is_stale(t: Option<&TransformStamped>, now_ns: i64, timeout_ns: i64) -> bool {
+    t.map(|t| now_ns - stamp_to_nanos(&t.header.stamp) > timeout_ns)
+        .unwrap_or(false)
+}
+
+/// true if holding is enabled and the transform has not been updated since the
+/// last publish cycle. the transform is still published, with reduced confidence.
+fn is_held(t: &TransformStamped, now_ns: i64, hold_ns: i64, period_ns: i64) -> bool {
+    hold_ns > 0 && now_ns - stamp_to_nanos(&t.header.stamp) > period_ns
+}
+
+/// lock the state, see `lock_or_recover`.
+fn lock_state<'a>(state: &'a Mutex<State>, logger: &str) -> MutexGuard<'a, State> {
+    lock_or_recover(state, "state", logger)
+}
+
+/// lock a mutex shared between tasks. if another task panicked while holding the
+/// lock the data is recovered rather than taking the whole estimator down with it.
+/// the poison is cleared, so this is logged once per panic.
+fn lock_or_recover<'a, T>(mutex: &'a Mutex<T>, name: &str, logger: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|e| {
+        r2r::log_warn!(logger, "{} mutex was poisoned, recovering", name);
+        mutex.clear_poison();
+        e.into_inner()
+    })
+}
+
+/// lock the raw markers, see `lock_or_recover`.
+fn lock_markers<'a>(markers: &'a Mutex<MarkerState>, logger: &str) -> MutexGuard<'a, MarkerState> {
+    lock_or_recover(markers, "markers", logger)
+}
+
+/// lock the locked transforms, see `lock_or_recover`.
+fn lock_locks<'a>(locks: &'a Mutex<Locks>, logger: &str) -> MutexGuard<'a, Locks> {
+    lock_or_recover(locks, "locks", logger)
+}
+
+/// how long a task waits for a lock and holds it. with the `lock_timing` feature
+/// this is logged at debug level every `LOCK_TIMING_PERIOD`, to see how much the
+/// tasks contend for the state. without it nothing is timed.
+#[cfg(feature = "lock_timing")]
+struct LockTiming {
+    name: &'static str,
+    count: u32,
+    wait: Duration,
+    hold: Duration,
+    max_hold: Duration,
+    since: Instant,
+}
+
+#[cfg(not(feature = "lock_timing"))]
+struct LockTiming;
+
+#[cfg(feature = "lock_timing")]
+const LOCK_TIMING_PERIOD: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "lock_timing")]
+impl LockTiming {
+    fn new(name: &'static str) -> LockTiming {
+        LockTiming {
+            name,
+            count: 0,
+            wait: Duration::ZERO,
+            hold: Duration::ZERO,
+            max_hold: Duration::ZERO,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, wait: Duration, hold: Duration, logger: &str) {
+        self.count += 1;
+        self.wait += wait;
+        self.hold += hold;
+        self.max_hold = self.max_hold.max(hold);
+        if self.since.elapsed() >= LOCK_TIMING_PERIOD {
+            let mean_us = |d: Duration| d.as_secs_f64() * 1e6 / self.count as f64;
+            r2r::log_debug!(logger, "{} locked {} times, waited {:.1} us and held it {:.1} us \
+                                     on average, at most {:.1} us", self.name, self.count,
+                            mean_us(self.wait), mean_us(self.hold),
+                            self.max_hold.as_secs_f64() * 1e6);
+            *self = LockTiming::new(self.name);
+        }
+    }
+}
+
+#[cfg(not(feature = "lock_timing"))]
+impl LockTiming {
+    fn new(_name: &'static str) -> LockTiming {
+        LockTiming
+    }
+}
+
+/// a guard of `lock_timed`, recording the hold time when it is dropped.
+#[cfg(feature = "lock_timing")]
+struct TimedGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    timing: &'a mut LockTiming,
+    wait: Duration,
+    locked: Instant,
+    logger: &'a str,
+}
+
+#[cfg(not(feature = "lock_timing"))]
+type TimedGuard<'a, T> = MutexGuard<'a, T>;
+
+#[cfg(feature = "lock_timing")]
+impl<T> std::ops::Deref for TimedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+#[cfg(feature = "lock_timing")]
+impl<T> std::ops::DerefMut for TimedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "lock_timing")]
+impl<T> Drop for TimedGuard<'_, T> {
+    fn drop(&mut self) {
+        self.timing.record(self.wait, self.locked.elapsed(), self.logger);
+    }
+}
+
+/// lock `mutex` like `lock_or_recover`, timed by `timing` with the `lock_timing` feature.
+#[cfg(feature = "lock_timing")]
+fn lock_timed<'a, T>(mutex: &'a Mutex<T>, name: &str, timing: &'a mut LockTiming,
+                     logger: &'a str) -> TimedGuard<'a, T> {
+    let requested = Instant::now();
+    let guard = lock_or_recover(mutex, name, logger);
+    let locked = Instant::now();
+    TimedGuard { guard, timing, wait: locked - requested, locked, logger }
+}
+
+#[cfg(not(feature = "lock_timing"))]
+fn lock_timed<'a, T>(mutex: &'a Mutex<T>, name: &str, _timing: &'a mut LockTiming,
+                     logger: &'a str) -> TimedGuard<'a, T> {
+    lock_or_recover(mutex, name, logger)
+}
+
+/// process a detection from `source`: filter it into the markers and recompute only
+/// what is derived from it. the markers are locked once per detection, the derived
+/// state only when something derived from the marker is recomputed, timed by
+/// `timings` (markers, state).
+#[allow(clippy::too_many_arguments)]
+fn process_marker(params: &MarkerParams, marker_state: &Mutex<MarkerState>, state: &Mutex<State>,
+                  frames: &Mutex<KnownTransforms>, timings: &mut [LockTiming; 2], source: String,
+                  msg: TransformStamped, now_ns: i64, stale_timeout_ns: i64, logger: &str) {
+    let [marker_timing, state_timing] = timings;
+    let mut marker_state = lock_timed(marker_state, "markers", marker_timing, logger);
+    let marker_state = &mut *marker_state;
+    if !params.interested_in.contains(&msg.child_frame_id) {
+        return;
+    }
+    *marker_state.marker_counts.entry(msg.child_frame_id.clone()).or_default() += 1;
+
+    // pose estimates of markers far from the camera are poor, and very close
+    // ones are most likely spurious.
+    let range = vector_from_msg(&msg.transform.translation).magnitude();
+    if range < params.min_range || range > params.max_range {
+        if marker_state.log_throttle.ready(&format!("range_{}", msg.child_frame_id),
+                                           Duration::from_secs(1)) {
+            r2r::log_warn!(logger, "marker {} rejected, range {:.2} m is outside \
+                                    [{:.2}, {:.2}]", msg.child_frame_id, range,
+                           params.min_range, params.max_range);
+        }
+        return;
+    }
+
+    // fuse the detection with the recent ones of the same marker by the other
+    // inputs, weighted by their inverse range. several cameras are fused in the
+    // target frame if there is one, so that all detections of a marker end up in
+    // the same frame, and otherwise only when they share a frame.
+    let id = msg.child_frame_id.clone();
+    let msg = if params.fuse {
+        let observation = Observation { transform: msg.clone(), weight: 1.0 / range.max(0.01) };
+        marker_state.observations.insert((source, id.clone()), observation);
+        let frames = lock_or_recover(frames, "frames", logger);
+        let fused = observations_to_fuse(&marker_state.observations, &msg,
+                                         params.fusion_window_ns, &params.target_frame,
+                                         &frames, now_ns, stale_timeout_ns);
+        match fuse_observations(&fused) {
+            Some(fused) => fused,
+            None => {
+                if marker_state.log_throttle.ready(&format!("fusion_{}", id),
+                                                   Duration::from_secs(1)) {
+                    r2r::log_warn!(logger, "marker {} cannot be expressed in {}, rejecting",
+                                   id, params.target_frame);
+                }
+                return;
+            }
+        }
+    } else {
+        msg
+    };
+
+    // update the marker, and recompute only what is derived from it.
+    // a rejected sample changes nothing that is derived from the marker.
+    let filter = marker_state.marker_filters.entry(id.clone()).or_default();
+    if !update_or_set(msg, &mut marker_state.markers, filter, &params.filter, now_ns,
+                      &mut marker_state.log_throttle, &mut marker_state.marker_events, logger) {
+        return;
+    }
+    let facade = params.facade_ids.contains(&id) || params.facade_extra_ids.contains(&id);
+    let gantry = params.gantry_ids.contains(&id) || params.gantry_extra_ids.contains(&id);
+    if !facade && !gantry && id != params.agv_id {
+        return;
+    }
+    let markers = &marker_state.markers;
+    let mut state = lock_timed(state, "state", state_timing, logger);
+    let state = &mut *state;
+    if facade {
+        update_pair_transform(pair_markers(markers, &params.facade_ids),
+                              &extra_markers(markers, &params.facade_extra_ids),
+                              &mut state.facade_transform, &mut state.facade_single_offset,
+                              &params.facade_pair, &mut state.log_throttle, logger);
+        state.facade_history.update(state.facade_transform.as_ref(), params.quality_window);
+    } else if gantry {
+        update_pair_transform(pair_markers(markers, &params.gantry_ids),
+                              &extra_markers(markers, &params.gantry_extra_ids),
+                              &mut state.gantry_transform, &mut state.gantry_single_offset,
+                              &params.gantry_pair, &mut state.log_throttle, logger);
+        state.gantry_history.update(state.gantry_transform.as_ref(), params.quality_window);
+    } else {
+        state.agv_transform = markers.get(&id).map(|agv_marker| {
+            let mut agv_transform = agv_marker.clone();
+            agv_transform.transform.translation.z = 3.27;
+            agv_transform.child_frame_id = params.agv_frame.clone();
+            agv_transform
+        });
+    }
+}
+
+/// publish a message, logging failures instead of panicking.
+fn publish<T: WrappedTypesupport>(publisher: &Publisher<T>, msg: &T, logger: &str) {
+    if let Err(e) = publisher.publish(msg) {
+        r2r::log_error!(logger, "could not publish: {}", e);
+    }
+}
+
+/// publish the transforms to every tf topic in `publishers`.
+fn publish_tf(publishers: &[Publisher<TFMessage>], transforms: Vec<TransformStamped>,
+              logger: &str) {
+    let msg = TFMessage { transforms };
+    for publisher in publishers {
+        publish(publisher, &msg, logger);
+    }
+}
+
+/// how good a lock of a structure is: how much it jittered recently, and how old
+/// its markers are.
+fn lock_quality(name: &str, history: &TransformHistory, markers: [Option<&TransformStamped>; 2],
+                now_ns: i64) -> String {
+    let (position, yaw) = history.jitter();
+    let ages: Vec<String> = markers.iter()
+        .map(|m| match m {
+            Some(m) => format!("{:.2} s", (now_ns - stamp_to_nanos(&m.header.stamp)) as f64 / 1e9),
+            None => "missing".into(),
+        })
+        .collect();
+    format!("{} jitter {:.4} m {:.4} rad over {} samples, marker ages {}", name, position, yaw,
+            history.samples.len(), ages.join(" and "))
+}
+
+/// a confidence in [0, 1] in a derived transform: the share of its markers that
+/// are present, times terms falling off with their mean age and the recent
+/// position and yaw jitter of the transform. 0 when the transform is absent.
+fn confidence(transform: Option<&TransformStamped>, history: &TransformHistory,
+              markers: [Option<&TransformStamped>; 2], now_ns: i64,
+              scales: &ConfidenceScales) -> f64 {
+    let present: Vec<&TransformStamped> = markers.iter().flatten().copied().collect();
+    if transform.is_none() || present.is_empty() {
+        return 0.0;
+    }
+    let age = present.iter()
+        .map(|m| ((now_ns - stamp_to_nanos(&m.header.stamp)) as f64 / 1e9).max(0.0))
+        .sum::<f64>() / present.len() as f64;
+    let (position, yaw) = history.jitter();
+    let falloff = |value: f64, scale: f64| if scale > 0.0 { (-value / scale).exp() } else { 1.0 };
+    present.len() as f64 / 2.0 * falloff(age, scales.age_secs) *
+        falloff(position, scales.position_jitter) * falloff(yaw, scales.yaw_jitter)
+}
+
+/// the locked pose for service responses, e.g.
+/// "gantry at x 1.000 y 2.000 z 1.930 m, yaw 1.5708 rad in camera".
+fn describe_lock(name: &str, t: &TransformStamped) -> String {
+    let p = &t.transform.translation;
+    format!("{} at x {:.3} y {:.3} z {:.3} m, yaw {:.4} rad in {}", name, p.x, p.y, p.z,
+            transform_yaw(t), t.header.frame_id)
+}
+
+/// the planar difference of a live transform from its lock, both in the same
+/// frame: the translation in x and y and the yaw, live minus locked.
+fn alignment_error(live: &TransformStamped, locked: &TransformStamped) -> Pose2D {
+    Pose2D {
+        x: live.transform.translation.x - locked.transform.translation.x,
+        y: live.transform.translation.y - locked.transform.translation.y,
+        theta: wrap_angle(transform_yaw(live) - transform_yaw(locked)),
+    }
+}
+
+/// the reason a structure cannot be locked right now, or None if it can.
+fn lock_unavailable(name: &str, transform: &Option<TransformStamped>,
+                    markers: [Option<&TransformStamped>; 2],
+                    now_ns: i64, max_age_ns: i64) -> Option<String> {
+    if transform.is_none() {
+        Some(format!("{} transform is missing", name))
+    } else if markers.iter().any(|m| is_stale(*m, now_ns, max_age_ns)) {
+        Some(format!("{} markers are about to go stale", name))
+    } else {
+        None
+    }
+}
+
+/// collect `count` distinct samples of both the gantry and the facade transform,
+/// polling the state every `period`. fails if either goes missing meanwhile.
+async fn collect_samples(state: &Mutex<State>, count: usize, period: Duration, logger: &str)
+                         -> Result<(Vec<TransformStamped>, Vec<TransformStamped>), String> {
+    let mut gantry_samples: Vec<TransformStamped> = vec![];
+    let mut facade_samples: Vec<TransformStamped> = vec![];
+    let mut interval = tokio::time::interval(period);
+    while gantry_samples.len() < count || facade_samples.len() < count {
+        interval.tick().await;
+        let state = lock_state(state, logger);
+        let current = [(&mut gantry_samples, &state.gantry_transform, "gantry"),
+                       (&mut facade_samples, &state.facade_transform, "facade")];
+        for (samples, transform, name) in current {
+            let t = transform.as_ref()
+                .ok_or_else(|| format!("{} transform went missing after {} samples",
+                                       name, samples.len()))?;
+            let new = samples.last().map(|last| last.header.stamp != t.header.stamp)
+                .unwrap_or(true);
+            if new && samples.len() < count {
+                samples.push(t.clone());
+            }
+        }
+    }
+    Ok((gantry_samples, facade_samples))
+}
+
+/// the average of a set of transforms, with the rms deviation of the samples
+/// from it in translation (m) and rotation (rad).
+fn average_transforms(samples: &[TransformStamped]) -> (TransformStamped, f64, f64) {
+    let n = samples.len() as f64;
+    let translations: Vec<Vector3<f64>> = samples.iter()
+        .map(|t| vector_from_msg(&t.transform.translation))
+        .collect();
+    let mean = translations.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, v| acc + v) / n;
+
+    // the average rotation is the dominant eigenvector of the sum of the outer
+    // products q q^T, found by power iteration starting from the first sample.
+    let rotations: Vec<Vector4<f64>> = samples.iter()
+        .map(|t| {
+            let q = quaternion_from_msg(&t.transform.rotation).normalize();
+            Vector4::new(q.v.x, q.v.y, q.v.z, q.s)
+        })
+        .collect();
+    let m = rotations.iter()
+        .map(|q| Matrix4::from_cols(q * q.x, q * q.y, q * q.z, q * q.w))
+        .fold(Matrix4::zero(), |acc, qq| acc + qq);
+    let mut v = rotations[0];
+    for _ in 0..32 {
+        v = (m * v).normalize();
+    }
+    let average_q = Quaternion::new(v.w, v.x, v.y, v.z);
+
+    let translation_spread = (translations.iter()
+        .map(|t| (t - mean).magnitude2())
+        .sum::<f64>() / n).sqrt();
+    let rotation_spread = (rotations.iter()
+        .map(|q| {
+            let angle = 2.0 * q.dot(v).abs().min(1.0).acos();
+            angle * angle
+        })
+        .sum::<f64>() / n).sqrt();
+
+    let mut average = samples[samples.len() - 1].clone();
+    average.transform.translation.x = mean.x;
+    average.transform.translation.y = mean.y;
+    average.transform.translation.z = mean.z;
+    set_msg_quaternion(&mut average.transform.rotation, average_q);
+    (average, translation_spread, rotation_spread)
+}
+
+/// a json summary of a live estimate, for the get_estimates service.
+fn estimate_json(t: &Option<TransformStamped>, held: bool) -> serde_json::Value {
+    match t {
+        Some(t) => {
+            let p = &t.transform.translation;
+            let q = &t.transform.rotation;
+            serde_json::json!({
+                "available": true,
+                "held": held,
+                "frame_id": t.header.frame_id,
+                "translation": [p.x, p.y, p.z],
+                "rotation": [q.x, q.y, q.z, q.w],
+                "yaw": transform_yaw(t),
+            })
+        }
+        None => serde_json::json!({ "available": false }),
+    }
+}
+
+/// write the locked transforms to `path` as json, so they survive a restart.
+fn save_locks(path: &str, facade: &Option<TransformStamped>,
+              gantry: &Option<TransformStamped>) -> Result<(), String> {
+    let json = serde_json::json!({ "facade": facade, "gantry": gantry });
+    let contents = serde_json::to_string_pretty(&json).map_err(|e| e.to_string())?;
+    std::fs::write(path, contents).map_err(|e| e.to_string())
+}
+
+/// read back the locked transforms written by `save_locks`, returned as (facade, gantry).
+fn load_locks(path: &str)
+              -> Result<(Option<TransformStamped>, Option<TransformStamped>), String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut json: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| e.to_string())?;
+    let facade = serde_json::from_value(json["facade"].take()).map_err(|e| e.to_string())?;
+    let gantry = serde_json::from_value(json["gantry"].take()).map_err(|e| e.to_string())?;
+    Ok((facade, gantry))
+}
+
+/// persist the current locks if a lock file is configured.
+fn store_locks(path: &str, locks: &Locks, logger: &str) {
+    if path.is_empty() {
+        return;
+    }
+    if let Err(e) = save_locks(path, &locks.facade, &locks.gantry) {
+        r2r::log_warn!(logger, "could not save locks to {}: {}", path, e);
+    }
+}
+
+const CSV_HEADER: &str = "stamp,facade_x,facade_y,facade_z,facade_yaw,\
+                          gantry_x,gantry_y,gantry_z,gantry_yaw,agv_x,agv_y,agv_z,agv_yaw";
+
+/// open the csv log for appending, writing the header if the file is new.
+fn open_csv_log(path: &str) -> std::io::Result<std::io::BufWriter<std::fs::File>> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let new = file.metadata()?.len() == 0;
+    let mut writer = std::io::BufWriter::new(file);
+    if new {
+        writeln!(writer, "{}", CSV_HEADER)?;
+    }
+    Ok(writer)
+}
+
+/// a csv log row with the stamp in seconds and the translation and yaw of each
+/// transform, left blank when it is missing.
+fn csv_row(now_ns: i64, transforms: &[&Option<TransformStamped>]) -> String {
+    let mut row = format!("{:.9}", now_ns as f64 / 1e9);
+    for t in transforms {
+        match t {
+            Some(t) => {
+                let p = &t.transform.translation;
+                row += &format!(",{},{},{},{}", p.x, p.y, p.z, transform_yaw(t));
+            }
+            None => row += ",,,,",
+        }
+    }
+    row
+}
+
+/// the qos profile for a preset name, falling back to the default profile.
+fn qos_preset(name: &str, logger: &str) -> r2r::QosProfile {
+    match name {
+        "default" => r2r::QosProfile::default(),
+        "sensor_data" => r2r::QosProfile::sensor_data(),
+        "system_default" => r2r::QosProfile::system_default(),
+        "transient_local" => r2r::QosProfile::default().transient_local(),
+        _ => {
+            r2r::log_warn!(logger, "unknown qos preset {}, using default", name);
+            r2r::QosProfile::default()
+        }
+    }
+}
+
+/// the qos profile `name`, a preset with optional `<name>_reliability`
+/// (reliable or best_effort), `<name>_durability` (volatile or transient_local)
+/// and `<name>_depth` overrides. a depth of 0 keeps the one of the preset.
+fn param_qos(node: &Node, name: &str, logger: &str) -> r2r::QosProfile {
+    let mut qos = qos_preset(&param_string(node, name, "default"), logger);
+    match param_string(node, &format!("{}_reliability", name), "").as_str() {
+        "" => (),
+        "reliable" => qos = qos.reliable(),
+        "best_effort" => qos = qos.best_effort(),
+        other => r2r::log_warn!(logger, "unknown {}_reliability {}, ignoring", name, other),
+    }
+    match param_string(node, &format!("{}_durability", name), "").as_str() {
+        "" => (),
+        "volatile" => qos = qos.volatile(),
+        "transient_local" => qos = qos.transient_local(),
+        other => r2r::log_warn!(logger, "unknown {}_durability {}, ignoring", name, other),
+    }
+    let depth = param_i64(node, &format!("{}_depth", name), 0);
+    if depth > 0 {
+        qos = qos.keep_last(depth as usize);
+    }
+    qos
+}
+
+/// lock the parameters of the node, see `lock_or_recover`.
+fn node_params(node: &Node) -> MutexGuard<'_, HashMap<String, ParameterValue>> {
+    lock_or_recover(&node.params, "params", node.logger())
+}
+
+/// read a floating point parameter, falling back to `default` when it is not set.
+fn param_f64(node: &Node, name: &str, default: f64) -> f64 {
+    match node_params(node).get(name) {
+        Some(ParameterValue::Double(d)) => *d,
+        Some(ParameterValue::Integer(i)) => *i as f64,
+        _ => default,
+    }
+}
+
+/// read an integer parameter, falling back to `default` when it is not set.
+fn param_i64(node: &Node, name: &str, default: i64) -> i64 {
+    match node_params(node).get(name) {
+        Some(ParameterValue::Integer(i)) => *i,
+        _ => default,
+    }
+}
+
+/// read a boolean parameter, falling back to `default` when it is not set.
+fn param_bool(node: &Node, name: &str, default: bool) -> bool {
+    match node_params(node).get(name) {
+        Some(ParameterValue::Bool(b)) => *b,
+        _ => default,
+    }
+}
+
+/// read a string parameter, falling back to `default` when it is not set.
+fn param_string(node: &Node, name: &str, default: &str) -> String {
+    match node_params(node).get(name) {
+        Some(ParameterValue::String(s)) => s.clone(),
+        _ => default.to_string(),
+    }
+}
+
+/// read a pair of strings from a string array parameter, falling back to
+/// `default` when it is not set or does not contain exactly two elements.
+fn param_string_pair(node: &Node, name: &str, default: [&str; 2]) -> [String; 2] {
+    match node_params(node).get(name) {
+        Some(ParameterValue::StringArray(v)) if v.len() == 2 => [v[0].clone(), v[1].clone()],
+        Some(ParameterValue::NotSet) | None => [default[0].to_string(), default[1].to_string()],
+        Some(other) => {
+            r2r::log_warn!(node.logger(), "parameter {} should be two strings, got {:?}. \
+                                           using default {:?}", name, other, default);
+            [default[0].to_string(), default[1].to_string()]
+        }
+    }
+}
+
+/// read a string array parameter, falling back to `default` when it is not set.
+fn param_strings(node: &Node, name: &str, default: &[String]) -> Vec<String> {
+    match node_params(node).get(name) {
+        Some(ParameterValue::StringArray(v)) => v.clone(),
+        Some(ParameterValue::NotSet) | None => default.to_vec(),
+        Some(other) => {
+            r2r::log_warn!(node.logger(), "parameter {} should be strings, got {:?}. \
+                                           using default {:?}", name, other, default);
+            default.to_vec()
+        }
+    }
+}
+
+/// read an offset transform from a `[x, y, z, roll, pitch, yaw]` double array
+/// parameter, in meters and radians with the rotations about the fixed x, y and z
+/// axes as in urdf. returns None when it is not set, or all zero.
+fn param_offset(node: &Node, name: &str) -> Option<TransformStamped> {
+    let v = match node_params(node).get(name) {
+        Some(ParameterValue::DoubleArray(v)) if v.len() == 6 => v.clone(),
+        Some(ParameterValue::NotSet) | None => return None,
+        Some(other) => {
+            r2r::log_warn!(node.logger(), "parameter {} should be six doubles \
+                                           [x, y, z, roll, pitch, yaw], got {:?}. \
+                                           not applying an offset", name, other);
+            return None;
+        }
+    };
+    if v.iter().all(|x| *x == 0.0) {
+        return None;
+    }
+
+    let mut offset = TransformStamped::default();
+    offset.transform.translation.x = v[0];
+    offset.transform.translation.y = v[1];
+    offset.transform.translation.z = v[2];
+    let q = Quaternion::from_angle_z(Rad(v[5])) * Quaternion::from_angle_y(Rad(v[4])) *
+        Quaternion::from_angle_x(Rad(v[3]));
+    set_msg_quaternion(&mut offset.transform.rotation, q);
+    Some(offset)
+}
+
+/// read a point from a `[x, y, z]` double array parameter, falling back to
+/// `default` when it is not set.
+fn param_point(node: &Node, name: &str, default: [f64; 3]) -> [f64; 3] {
+    match node_params(node).get(name) {
+        Some(ParameterValue::DoubleArray(v)) if v.len() == 3 => [v[0], v[1], v[2]],
+        Some(ParameterValue::NotSet) | None => default,
+        Some(other) => {
+            r2r::log_warn!(node.logger(), "parameter {} should be three doubles [x, y, z], \
+                                           got {:?}. using default {:?}", name, other, default);
+            default
+        }
+    }
+}
+
+/// detections of the facade, gantry and agv markers (in the order of `ids`) as a
+/// camera looking down would see them at time `now`, around the `centers` of the
+/// facade, gantry and agv. the facade is fixed with a yaw of 0, the gantry moves
+/// back and forth along x with a yaw of pi/2, and the agv drives in a circle.
+fn simulated_markers(ids: &[String; 5], centers: &[[f64; 3]; 3], now: Duration)
+                     -> Vec<TransformStamped> {
+    let t = now.as_secs_f64();
+    let [facade, gantry, agv] = *centers;
+    let gantry_x = gantry[0] + 0.3 * (0.5 * t).sin();
+    let positions = [
+        ([facade[0] - 0.5, facade[1], facade[2]], 0.0),
+        ([facade[0] + 0.5, facade[1], facade[2]], 0.0),
+        ([gantry_x, gantry[1] - 0.4, gantry[2]], std::f64::consts::FRAC_PI_2),
+        ([gantry_x, gantry[1] + 0.4, gantry[2]], std::f64::consts::FRAC_PI_2),
+        ([agv[0] + 0.2 * (0.3 * t).cos(), agv[1] + 0.2 * (0.3 * t).sin(), agv[2]], 0.3 * t),
+    ];
+    ids.iter().zip(positions.iter()).map(|(id, (position, yaw))| {
+        let mut marker = TransformStamped::default();
+        marker.header.stamp = r2r::Clock::to_builtin_time(&now);
+        marker.header.frame_id = "camera".into();
+        marker.child_frame_id = id.clone();
+        marker.transform.translation.x = position[0];
+        marker.transform.translation.y = position[1];
+        marker.transform.translation.z = position[2];
+        set_msg_quaternion(&mut marker.transform.rotation, Quaternion::from_angle_z(Rad(*yaw)));
+        marker
+    }).collect()
+}
+
+/// marker parameters close to the defaults for the facade, gantry and agv markers
+/// `ids` (in the order of `simulated_markers`), with a median window of 5.
+fn simulated_marker_params(ids: &[String; 5]) -> MarkerParams {
+    let pair = |name: &str, height| PairParams {
+        name: name.into(),
+        child_frame_id: format!("{}_aruco", name),
+        height: Some(height),
+        midpoint: false,
+        spacing: 0.0,
+        spacing_tolerance: 0.1,
+        spacing_drop: false,
+        disambiguate_yaw: false,
+        marker_yaw_weight: 0.0,
+        yaw_continuity: false,
+        front_yaw: None,
+        flip: false,
+        single_marker_fallback: true,
+        offset: None,
+    };
+    MarkerParams {
+        interested_in: ids.to_vec(),
+        min_range: 0.0,
+        max_range: 100.0,
+        fuse: false,
+        fusion_window_ns: 100_000_000,
+        target_frame: "".into(),
+        filter: FilterParams {
+            smoothing: Smoothing { translation: [10.0; 3], rotation: 10.0 },
+            marker_smoothing: HashMap::new(),
+            time_constant: None,
+            median_window: 5,
+            max_jump_meters: 0.5,
+            jump_accept_count: 3,
+            max_tilt_xy: 0.2,
+            min_up_z: 0.9,
+            max_future_skew_ns: 0,
+            frozen_epsilon: 1e-6,
+            frozen_timeout_ns: 0,
+            frozen_is_stale: false,
+        },
+        facade_ids: [ids[0].clone(), ids[1].clone()],
+        gantry_ids: [ids[2].clone(), ids[3].clone()],
+        facade_extra_ids: vec![],
+        gantry_extra_ids: vec![],
+        facade_pair: pair("facade", 3.57),
+        gantry_pair: pair("gantry", 1.93),
+        quality_window: 20,
+        agv_id: ids[4].clone(),
+        agv_frame: "agv_aruco".into(),
+    }
+}
+
+/// feed `rounds` rounds of the simulated markers at 60 hz through `process_marker`,
+/// with `simulated_marker_params`. returns the number of detections and how long
+/// they took, for benches/marker_throughput.rs. panics if the facade or the gantry
+/// was not derived, so that nothing is measured by mistake.
+#[allow(dead_code)]
+pub(crate) fn marker_throughput(rounds: u32) -> (usize, Duration) {
+    let ids = ["aruco_0".to_string(), "aruco_1".to_string(), "aruco_2".to_string(),
+               "aruco_15".to_string(), "aruco_5".to_string()];
+    let centers = [[0.0, 0.0, 2.0], [0.0, 0.0, 1.5], [1.0, 1.0, 0.5]];
+    let detections: Vec<TransformStamped> = (0..rounds)
+        .flat_map(|i| simulated_markers(&ids, &centers, Duration::from_secs(i as u64) / 60))
+        .collect();
+    let params = simulated_marker_params(&ids);
+    let marker_state = Mutex::new(MarkerState::default());
+    let state = Mutex::new(State::default());
+    let frames = Mutex::new(KnownTransforms::new());
+    let mut timings = [LockTiming::new("markers"), LockTiming::new("state")];
+    let count = detections.len();
+    let start = Instant::now();
+    for msg in detections {
+        let now_ns = stamp_to_nanos(&msg.header.stamp);
+        process_marker(&params, &marker_state, &state, &frames, &mut timings,
+                       "simulate".into(), msg, now_ns, 1_000_000_000, "throughput");
+    }
+    let elapsed = start.elapsed();
+
+    let state = lock_state(&state, "throughput");
+    assert!(state.facade_transform.is_some() && state.gantry_transform.is_some(),
+            "the simulated markers did not derive the facade and gantry");
+    (count, elapsed)
+}
 
-    // markers 2 and 15 define the gantry position
-    marker_2: Option<TransformStamped>,
-    marker_15: Option<TransformStamped>,
+/// completes on ctrl-c or sigterm.
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("could not install sigterm handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = sigterm.recv() => (),
+    }
+}
 
-    // marker 5 is the agv
-    marker_5: Option<TransformStamped>,
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let ros_ctx = Context::create()?;
+    let mut node = Node::create(ros_ctx, "gantry_position_estimator", "")?;
 
-    // computed results
-    facade_transform: Option<TransformStamped>,
-    gantry_transform: Option<TransformStamped>,
-    agv_transform: Option<TransformStamped>,
+    // smoothing_factor is the default of the translation and rotation factors,
+    // and the translation factor the default of the per axis ones.
+    let smoothing_factor_param = |name: &str, default: f64| {
+        let factor = param_f64(&node, name, default);
+        if factor < 1.0 {
+            r2r::log_warn!(node.logger(), "{} {} is below 1.0, clamping to 1.0", name, factor);
+            1.0
+        } else {
+            factor
+        }
+    };
+    let smoothing_factor = smoothing_factor_param("smoothing_factor", 10.0);
+    let translation_factor =
+        smoothing_factor_param("translation_smoothing_factor", smoothing_factor);
+    let smoothing = Smoothing {
+        translation: [
+            smoothing_factor_param("smoothing_factor_x", translation_factor),
+            smoothing_factor_param("smoothing_factor_y", translation_factor),
+            smoothing_factor_param("smoothing_factor_z", translation_factor),
+        ],
+        rotation: smoothing_factor_param("rotation_smoothing_factor", smoothing_factor),
+    };
+    // per marker factors as `marker_smoothing_factor.<marker id>`, for both the
+    // translation and the rotation of that marker.
+    let marker_smoothing_names: Vec<String> = node_params(&node).keys()
+        .filter(|name| name.starts_with("marker_smoothing_factor."))
+        .cloned()
+        .collect();
+    let marker_smoothing: HashMap<String, Smoothing> = marker_smoothing_names.iter()
+        .map(|name| {
+            let factor = smoothing_factor_param(name, smoothing_factor);
+            let id = name["marker_smoothing_factor.".len()..].to_string();
+            (id, Smoothing { translation: [factor; 3], rotation: factor })
+        })
+        .collect();
+    for (id, smoothing) in &marker_smoothing {
+        r2r::log_info!(node.logger(), "smoothing factor of {} is {}", id, smoothing.rotation);
+    }
+    // the filter moves 1/factor towards every sample in the legacy "samples" mode,
+    // or by a time constant in the "time" mode.
+    let filter_mode = param_string(&node, "filter_mode", "samples");
+    let time_constant = match filter_mode.as_str() {
+        "samples" => None,
+        "time" => Some(param_f64(&node, "filter_tau_secs", 0.5).max(0.0)),
+        _ => {
+            r2r::log_warn!(node.logger(), "unknown filter_mode {}, using samples", filter_mode);
+            None
+        }
+    };
+    let median_window = param_i64(&node, "median_window", 5).max(1) as usize;
+    let filter_params = FilterParams {
+        smoothing,
+        marker_smoothing,
+        time_constant,
+        median_window,
+        max_jump_meters: param_f64(&node, "max_jump_meters", 0.5),
+        jump_accept_count: param_i64(&node, "jump_accept_count", 5).max(1) as usize,
+        max_tilt_xy: param_f64(&node, "max_tilt_xy", 0.2),
+        min_up_z: param_f64(&node, "min_up_z", 0.9),
+        max_future_skew_ns: (param_f64(&node, "max_future_skew", 0.5).max(0.0) * 1e9) as i64,
+        frozen_epsilon: param_f64(&node, "frozen_epsilon", 1e-6),
+        frozen_timeout_ns: (param_f64(&node, "frozen_timeout", 0.0).max(0.0) * 1e9) as i64,
+        frozen_is_stale: param_bool(&node, "frozen_is_stale", false),
+    };
 
-    // locked results
-    locked_facade_transform: Option<TransformStamped>,
-    locked_gantry_transform: Option<TransformStamped>,
-}
+    // accepted distance of a marker from the camera
+    let min_marker_range = param_f64(&node, "min_marker_range", 0.0);
+    let max_marker_range = param_f64(&node, "max_marker_range", 100.0);
 
-fn update_or_set(new: TransformStamped, maybe_old: &mut Option<TransformStamped>) {
-    if let Some(x) = maybe_old.as_mut() {
-        *x = filter_transform(new, x.clone());
-    } else {
-        println!("marker is live {}", new.child_frame_id);
-        *maybe_old = Some(new)
+    // can be changed while running, see the parameter handler below.
+    // stale_timeout_sec is accepted as another name of stale_timeout_secs.
+    let stale_timeout_secs = param_f64(&node, "stale_timeout_secs",
+                                       param_f64(&node, "stale_timeout_sec", 5.0));
+    let stale_timeout = Arc::new(AtomicI64::new((stale_timeout_secs * 1e9) as i64));
+    // when no detection at all has arrived for this long the detector is assumed
+    // dead, and `measured` is forced false. 0 disables the watchdog, as detectors
+    // usually publish nothing while they see no markers.
+    let input_timeout = Duration::from_secs_f64(param_f64(&node, "input_timeout", 0.0).max(0.0));
+    // keep publishing derived transforms for this long after their markers stop
+    // updating, 0 disables holding.
+    let hold_ns = (param_f64(&node, "hold_duration", 0.0) * 1e9) as i64;
+
+    let override_height = param_bool(&node, "override_height", true);
+    let use_marker_midpoint = param_bool(&node, "use_marker_midpoint", true);
+    let marker_spacing_tolerance = param_f64(&node, "marker_spacing_tolerance", 0.1);
+    let marker_spacing_drop = param_bool(&node, "marker_spacing_drop", true);
+    let disambiguate_yaw = param_bool(&node, "disambiguate_yaw", false);
+    let marker_yaw_weight = param_f64(&node, "marker_yaw_weight", 0.0).clamp(0.0, 1.0);
+    let yaw_continuity = param_bool(&node, "yaw_continuity", false);
+    let single_marker_fallback = param_bool(&node, "single_marker_fallback", false);
+
+    // child frame ids of the published transforms, so that several estimators
+    // can publish to the same tf tree.
+    let facade_frame = param_string(&node, "facade_frame", "facade_aruco");
+    let gantry_frame = param_string(&node, "gantry_frame", "gantry_aruco");
+    let agv_frame = param_string(&node, "agv_frame", "agv_aruco");
+    let facade_locked_frame = param_string(&node, "facade_locked_frame", "facade_locked");
+    let gantry_locked_frame = param_string(&node, "gantry_locked_frame", "gantry_locked");
+
+    let facade_pair = PairParams {
+        name: "facade".into(),
+        child_frame_id: facade_frame,
+        height: Some(param_f64(&node, "facade_height", 3.57)).filter(|_| override_height),
+        midpoint: use_marker_midpoint,
+        spacing: param_f64(&node, "facade_marker_spacing", 0.0),
+        spacing_tolerance: marker_spacing_tolerance,
+        spacing_drop: marker_spacing_drop,
+        disambiguate_yaw,
+        marker_yaw_weight,
+        yaw_continuity,
+        front_yaw: Some(param_f64(&node, "facade_front_yaw_deg", f64::NAN).to_radians())
+            .filter(|yaw| yaw.is_finite()),
+        flip: param_bool(&node, "facade_flip", false),
+        single_marker_fallback,
+        offset: param_offset(&node, "facade_offset_xyz_rpy"),
+    };
+    let gantry_pair = PairParams {
+        name: "gantry".into(),
+        child_frame_id: gantry_frame,
+        height: Some(param_f64(&node, "gantry_height", 1.93)).filter(|_| override_height),
+        midpoint: use_marker_midpoint,
+        spacing: param_f64(&node, "gantry_marker_spacing", 0.0),
+        spacing_tolerance: marker_spacing_tolerance,
+        spacing_drop: marker_spacing_drop,
+        disambiguate_yaw,
+        marker_yaw_weight,
+        yaw_continuity,
+        front_yaw: Some(param_f64(&node, "gantry_front_yaw_deg", f64::NAN).to_radians())
+            .filter(|yaw| yaw.is_finite()),
+        flip: param_bool(&node, "gantry_flip", false),
+        single_marker_fallback,
+        offset: param_offset(&node, "gantry_offset_xyz_rpy"),
+    };
+
+    // the yaw of the facade/gantry is given by the direction from the
+    // first to the second marker, see `pair_transform`.
+    let facade_marker_ids = param_string_pair(&node, "facade_markers", ["aruco_0", "aruco_1"]);
+    let gantry_marker_ids = param_string_pair(&node, "gantry_markers", ["aruco_2", "aruco_15"]);
+    // more markers along the line between the pair, to average the yaw over
+    let facade_extra_ids = param_strings(&node, "facade_extra_markers", &[]);
+    let gantry_extra_ids = param_strings(&node, "gantry_extra_markers", &[]);
+    let agv_marker_id = param_string(&node, "agv_marker", "aruco_5");
+
+    let pose_base_variance = param_f64(&node, "pose_base_variance", 0.0001);
+    let pose_variance_per_sec = param_f64(&node, "pose_variance_per_sec", 0.001);
+
+    // the stamp of the published transforms. "mixed" keeps the stamp of the
+    // measurement on the live transforms and stamps the locked ones with now,
+    // "measurement" and "now" use one or the other for all of them.
+    let mut stamp_source = param_string(&node, "stamp_source", "mixed");
+    if !["mixed", "measurement", "now"].contains(&stamp_source.as_str()) {
+        r2r::log_warn!(node.logger(), "unknown stamp_source {}, using mixed", stamp_source);
+        stamp_source = "mixed".into();
     }
-}
 
-/// apply a low-pass filter to the position in the camera frame on incoming data
-fn filter_transform(new: TransformStamped, old: TransformStamped) -> TransformStamped {
-    let mut new_transform = new.clone();
+    let mut publish_rate_hz = param_f64(&node, "publish_rate_hz", 10.0);
+    if publish_rate_hz <= 0.0 {
+        r2r::log_warn!(node.logger(), "publish_rate_hz must be positive, using 10 Hz");
+        publish_rate_hz = 10.0;
+    }
 
-    let smooth = 10.0;
+    // how long both transforms have to be present (missing) before measured turns true (false).
+    let measured_on_ns = (param_f64(&node, "measured_on_secs", 0.0) * 1e9) as i64;
+    let measured_off_ns = (param_f64(&node, "measured_off_secs", 0.0) * 1e9) as i64;
 
-    let nx = new.transform.translation.x;
-    let ny = new.transform.translation.y;
-    let nz = new.transform.translation.z;
+    // the gantry velocity is low-pass filtered like the markers, each sample moves
+    // it 1/velocity_smoothing_factor of the way.
+    let velocity_smoothing_factor = param_f64(&node, "velocity_smoothing_factor", 5.0).max(1.0);
+    // publish a zero velocity while there is no estimate, instead of nothing.
+    let publish_zero_velocity = param_bool(&node, "publish_zero_velocity", false);
 
-    let ox = old.transform.translation.x;
-    let oy = old.transform.translation.y;
-    let oz = old.transform.translation.z;
+    // variance of every agv_odom twist component
+    let twist_variance = param_f64(&node, "twist_variance", 0.01);
 
-    let diff_x = (nx - ox) / smooth;
-    let diff_y = (ny - oy) / smooth;
-    let diff_z = (nz - oz) / smooth;
+    // publish NaN as the gantry facade distance while either is missing, instead of nothing.
+    let publish_nan_distance = param_bool(&node, "publish_nan_distance", false);
 
-    new_transform.transform.translation.x = ox + diff_x;
-    new_transform.transform.translation.y = oy + diff_y;
-    new_transform.transform.translation.z = oz + diff_z;
+    // lock automatically once the facade and gantry positions have stayed within
+    // the threshold (rms, meters) for the duration.
+    let auto_lock = param_bool(&node, "auto_lock", false);
+    let stability_threshold = param_f64(&node, "stability_threshold", 0.005);
+    let stability_duration_ns = (param_f64(&node, "stability_duration", 2.0) * 1e9) as i64;
 
-    new_transform
-}
+    // whether the reset service also clears the locks.
+    let reset_clears_locks = param_bool(&node, "reset_clears_locks", false);
 
-/// filter out bad measurements
-#[allow(dead_code)]
-fn marker_ok(t: &TransformStamped) -> bool {
-    //
-    let up = Vector3::unit_z();
-    let q0 = Quaternion::new(t.transform.rotation.w, t.transform.rotation.x,
-                             t.transform.rotation.y, t.transform.rotation.z);
-    let rotated =  q0 * up;
-    rotated.x.abs() < 0.2 && rotated.y.abs() < 0.2 && rotated.z.abs() > 0.9
-}
+    // number of recent facade and gantry transforms the lock quality is computed over
+    let quality_window = param_i64(&node, "quality_window", 20).max(1) as usize;
+    let confidence_scales = ConfidenceScales {
+        age_secs: param_f64(&node, "confidence_age_scale", 0.5),
+        position_jitter: param_f64(&node, "confidence_position_jitter_scale", 0.01),
+        yaw_jitter: param_f64(&node, "confidence_yaw_jitter_scale", 0.02),
+    };
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let ros_ctx = Context::create()?;
-    let mut node = Node::create(ros_ctx, "gantry_position_estimator", "")?;
+    // the trigger locks onto the average of this many gantry and facade samples.
+    let calibration_samples = param_i64(&node, "calibration_samples", 1).max(1) as usize;
+
+    // locks are kept in this file across restarts, empty disables persistence.
+    let lock_file = param_string(&node, "lock_file", "");
+
+    // append the estimates to this csv file every cycle, empty disables the log.
+    let csv_log_path = param_string(&node, "csv_log_path", "");
+    let mut csv_log = if csv_log_path.is_empty() {
+        None
+    } else {
+        match open_csv_log(&csv_log_path) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                r2r::log_error!(node.logger(), "could not open csv log {}: {}", csv_log_path, e);
+                None
+            }
+        }
+    };
 
-    let sub = node.subscribe::<TransformStamped>("/aruco", r2r::QosProfile::default())?;
-    let tf_pub = node.create_publisher::<TFMessage>("/rita/tf", r2r::QosProfile::default())?;
-    let tf_pub2 = node.create_publisher::<TFMessage>("/tf", r2r::QosProfile::default())?;
+    let logger = node.logger().to_string();
+
+    // the tf publishers default to the depth tf2 uses for /tf
+    let aruco_qos = param_qos(&node, "aruco_qos", &logger);
+    let tf_qos = param_qos(&node, "tf_qos", &logger);
+    let aruco_topic = param_string(&node, "aruco_topic", "/aruco");
+    // several cameras can publish detections on topics of their own, they are
+    // fused per marker, see `fuse_observations`.
+    let aruco_topics = param_strings(&node, "aruco_topics", &[aruco_topic]);
+    // detections of a marker by different inputs less than this apart are fused
+    let fusion_window_ns = (param_f64(&node, "fusion_window", 0.1).max(0.0) * 1e9) as i64;
+    let aruco_tf_topic = param_string(&node, "aruco_tf_topic", "/aruco_tf");
+    let tf_topic = param_string(&node, "tf_topic", "/tf");
+    let rita_tf_topic = param_string(&node, "rita_tf_topic", "/rita/tf");
+    let measured_topic = param_string(&node, "measured_topic", "measured");
+    // detections come as single transforms on aruco_topics, as transform arrays on
+    // aruco_tf_topic, or both. either way they end up in the same stream, one item
+    // per message tagged with the topic it came from.
+    let mut aruco_input = param_string(&node, "aruco_input", "transform");
+    if !["transform", "tf", "both"].contains(&aruco_input.as_str()) {
+        r2r::log_warn!(&logger, "unknown aruco_input {}, using transform", aruco_input);
+        aruco_input = "transform".into();
+    }
+    // generate the detections internally instead, for testing without a camera.
+    let simulate = param_bool(&node, "simulate", false);
+    let simulated_centers = [
+        param_point(&node, "simulate_facade_xyz", [0.0, 0.0, 2.0]),
+        param_point(&node, "simulate_gantry_xyz", [0.0, 0.0, 1.5]),
+        param_point(&node, "simulate_agv_xyz", [1.0, 1.0, 0.5]),
+    ];
+    // high rates are useful to see how the tasks contend for the state
+    let simulate_rate_hz = param_f64(&node, "simulate_rate_hz", 20.0).max(0.1);
+    let mut inputs = vec![];
+    if simulate {
+        r2r::log_info!(&logger, "simulating the markers, not subscribing to detections");
+    } else if aruco_input != "tf" {
+        for topic in &aruco_topics {
+            let source = topic.clone();
+            let input = node.subscribe::<TransformStamped>(topic, aruco_qos.clone())?
+                .map(move |t| (source.clone(), vec![t]));
+            inputs.push(input.boxed());
+        }
+    }
+    if !simulate && aruco_input != "transform" {
+        let source = aruco_tf_topic.clone();
+        let tf_input = node.subscribe::<TFMessage>(&aruco_tf_topic, aruco_qos)?
+            .map(move |msg| (source.clone(), msg.transforms));
+        inputs.push(tf_input.boxed());
+    }
+    // the transforms go to both tf topics, unless disabled. some deployments do
+    // not want them on the global /tf.
+    let mut tf_pubs = vec![];
+    if param_bool(&node, "publish_rita_tf", true) {
+        tf_pubs.push(node.create_publisher::<TFMessage>(&rita_tf_topic, tf_qos.clone())?);
+    }
+    // publish_global_tf is accepted as another name of publish_tf
+    if param_bool(&node, "publish_tf", param_bool(&node, "publish_global_tf", true)) {
+        tf_pubs.push(node.create_publisher::<TFMessage>(&tf_topic, tf_qos)?);
+    }
+    // the locked transforms can go to their own transient local topic instead,
+    // so that late joining subscribers get them right away.
+    // with locked_tf_static they are static transforms on /tf_static instead, only
+    // published when they change.
+    let locked_tf_static = param_bool(&node, "locked_tf_static", false);
+    let mut locked_tf_topic = param_string(&node, "locked_tf_topic", "");
+    if locked_tf_static {
+        locked_tf_topic = "/tf_static".into();
+    }
+    let locked_tf_pub = if locked_tf_topic.is_empty() {
+        None
+    } else {
+        let qos = r2r::QosProfile::default().transient_local().keep_last(1);
+        Some(node.create_publisher::<TFMessage>(&locked_tf_topic, qos)?)
+    };
 
     let mut trigger_srv = node.create_service::<Trigger::Service>("trigger")?;
-    let ok_pub = node.create_publisher::<Bool>("measured", r2r::QosProfile::default())?;
+    let mut clear_lock_srv = node.create_service::<Trigger::Service>("clear_lock")?;
+    let lock_facade_srv = node.create_service::<Trigger::Service>("lock_facade")?;
+    let lock_gantry_srv = node.create_service::<Trigger::Service>("lock_gantry")?;
+    let mut get_estimates_srv = node.create_service::<Trigger::Service>("get_estimates")?;
+    let mut reset_srv = node.create_service::<Trigger::Service>("reset")?;
+    let ok_pub = node.create_publisher::<Bool>(&measured_topic, r2r::QosProfile::default())?;
+    let agv_ok_pub = node.create_publisher::<Bool>("agv_measured", r2r::QosProfile::default())?;
+    let detector_alive_pub = node.create_publisher::<Bool>(
+        "detector_alive", r2r::QosProfile::default())?;
+    let markers_pub = node.create_publisher::<MarkerArray>("estimator_markers",
+                                                           r2r::QosProfile::default())?;
+    let facade_pose_pub = node.create_publisher::<PoseWithCovarianceStamped>(
+        "facade_pose", r2r::QosProfile::default())?;
+    let gantry_pose_pub = node.create_publisher::<PoseWithCovarianceStamped>(
+        "gantry_pose", r2r::QosProfile::default())?;
+    let marker_events_pub = node.create_publisher::<r2r::std_msgs::msg::String>(
+        "marker_events", r2r::QosProfile::default())?;
+    let auto_lock_pub = node.create_publisher::<r2r::std_msgs::msg::String>(
+        "auto_lock", r2r::QosProfile::default())?;
+    let diagnostics_pub = node.create_publisher::<DiagnosticArray>(
+        "/diagnostics", r2r::QosProfile::default())?;
+    let marker_status_pub = node.create_publisher::<r2r::std_msgs::msg::String>(
+        "marker_status", r2r::QosProfile::default())?;
+    let marker_rates_pub = node.create_publisher::<r2r::std_msgs::msg::String>(
+        "marker_rates", r2r::QosProfile::default())?;
+    let gantry_in_facade_pub = node.create_publisher::<TransformStamped>(
+        "gantry_in_facade", r2r::QosProfile::default())?;
+    let gantry_in_facade_yaw_pub = node.create_publisher::<Float64>(
+        "gantry_in_facade_yaw", r2r::QosProfile::default())?;
+    let facade_yaw_pub = node.create_publisher::<Float64>(
+        "facade_yaw_deg", r2r::QosProfile::default())?;
+    let gantry_yaw_pub = node.create_publisher::<Float64>(
+        "gantry_yaw_deg", r2r::QosProfile::default())?;
+    let facade_confidence_pub = node.create_publisher::<Float64>(
+        "facade_confidence", r2r::QosProfile::default())?;
+    let gantry_confidence_pub = node.create_publisher::<Float64>(
+        "gantry_confidence", r2r::QosProfile::default())?;
+    let facade_alignment_pub = node.create_publisher::<Pose2D>(
+        "facade_alignment_error", r2r::QosProfile::default())?;
+    let gantry_alignment_pub = node.create_publisher::<Pose2D>(
+        "gantry_alignment_error", r2r::QosProfile::default())?;
+    let gantry_velocity_pub = node.create_publisher::<TwistStamped>(
+        "gantry_velocity", r2r::QosProfile::default())?;
+    let agv_velocity_pub = node.create_publisher::<TwistStamped>(
+        "agv_velocity", r2r::QosProfile::default())?;
+    let agv_odom_pub = node.create_publisher::<Odometry>(
+        "agv_odom", r2r::QosProfile::default())?;
+    let distance_pub = node.create_publisher::<Float64>("gantry_facade_distance",
+                                                        r2r::QosProfile::default())?;
+
+    // transforms between the frames, from /tf and /tf_static. the target frames are
+    // looked up through chains of them, the ones from /tf only while they are fresh.
+    // the locked transforms can have a target frame of their own, e.g. map, so
+    // that they are rooted in the fixed part of the tf tree.
+    // a fixed pose of the camera in the map can be given directly instead. the
+    // outputs are then published in the map frame, unless `publish_camera_frame`.
+    let map_frame = param_string(&node, "map_frame", "map");
+    let camera_to_map = param_offset(&node, "camera_to_map_xyz_rpy").map(|mut t| {
+        t.header.frame_id = map_frame.clone();
+        t.child_frame_id = param_string(&node, "camera_frame", "camera");
+        t
+    });
+    let default_target_frame = match camera_to_map {
+        Some(_) if !param_bool(&node, "publish_camera_frame", false) => map_frame.as_str(),
+        _ => "",
+    };
+    let target_frame = param_string(&node, "target_frame", default_target_frame);
+    let locked_target_frame = param_string(&node, "locked_target_frame", &target_frame);
+    let mut tf_targets = vec![target_frame.clone()];
+    if locked_target_frame != target_frame {
+        tf_targets.push(locked_target_frame.clone());
+    }
+    tf_targets.retain(|frame| !frame.is_empty());
+    let mut known_frames = KnownTransforms::new();
+    if let Some(transform) = camera_to_map {
+        known_frames.insert((transform.header.frame_id.clone(), transform.child_frame_id.clone()),
+                            KnownTransform { transform, is_static: true });
+    }
+    let frames = Arc::new(Mutex::new(known_frames));
+    let mut listener_handles = vec![];
+    if !tf_targets.is_empty() {
+        let topics = [("/tf", r2r::QosProfile::default(), false),
+                      ("/tf_static", r2r::QosProfile::default().transient_local(), true)];
+        for (topic, qos, is_static) in topics {
+            let tf_sub = node.subscribe::<TFMessage>(topic, qos)?;
+            let frames_task = frames.clone();
+            let logger_task = logger.clone();
+            listener_handles.push(tokio::spawn(tf_sub.for_each(move |msg| {
+                let mut frames = lock_or_recover(&frames_task, "frames", &logger_task);
+                for transform in msg.transforms {
+                    let key = (transform.header.frame_id.clone(), transform.child_frame_id.clone());
+                    frames.insert(key, KnownTransform { transform, is_static });
+                }
+                future::ready(())
+            })));
+        }
+    }
+
+    // r2r clocks do not follow /clock, so with use_sim_time we keep track of it
+    // ourselves. all tasks get their time from a `TimeSource`.
+    let use_sim_time = param_bool(&node, "use_sim_time", false);
+    let sim_time = if use_sim_time {
+        let sim_time = Arc::new(AtomicI64::new(0));
+        let sim_time_task = sim_time.clone();
+        let qos = r2r::QosProfile::default().keep_last(1).best_effort();
+        let clock_sub = node.subscribe::<r2r::rosgraph_msgs::msg::Clock>("/clock", qos)?;
+        listener_handles.push(tokio::spawn(clock_sub.for_each(move |msg| {
+            sim_time_task.store(stamp_to_nanos(&msg.clock), Ordering::SeqCst);
+            future::ready(())
+        })));
+        Some(sim_time)
+    } else {
+        None
+    };
+
+    let mut clock = TimeSource::create(&sim_time)?;
 
-    let mut clock = r2r::Clock::create(r2r::ClockType::RosTime)?;
+    // follow changes of the parameters that are allowed to change at runtime
+    let (param_handler, param_events) = node.make_parameter_handler()?;
+    listener_handles.push(tokio::spawn(param_handler));
+    let stale_timeout_task = stale_timeout.clone();
+    let logger_task = logger.clone();
+    listener_handles.push(tokio::spawn(param_events.for_each(move |(name, value)| {
+        if name == "stale_timeout_secs" || name == "stale_timeout_sec" {
+            let secs = match value {
+                ParameterValue::Double(secs) => Some(secs),
+                ParameterValue::Integer(secs) => Some(secs as f64),
+                _ => None,
+            };
+            match secs {
+                Some(secs) if secs > 0.0 => {
+                    stale_timeout_task.store((secs * 1e9) as i64, Ordering::SeqCst);
+                    r2r::log_info!(&logger_task, "stale timeout set to {} s", secs);
+                }
+                _ => r2r::log_warn!(&logger_task, "ignoring {} {:?}, expected a positive \
+                                                   number", name, value),
+            }
+        }
+        future::ready(())
+    })));
 
+    let mut initial_locks = Locks::default();
+    if !lock_file.is_empty() && std::path::Path::new(&lock_file).exists() {
+        match load_locks(&lock_file) {
+            Ok((facade, gantry)) => {
+                r2r::log_info!(&logger, "loaded locks from {} (facade: {}, gantry: {})",
+                               lock_file, facade.is_some(), gantry.is_some());
+                initial_locks.facade = facade;
+                initial_locks.gantry = gantry;
+            }
+            Err(e) => r2r::log_warn!(&logger, "could not load locks from {}: {}", lock_file, e),
+        }
+    }
+    let marker_state = Arc::new(Mutex::new(MarkerState::default()));
     let state = Arc::new(Mutex::new(State::default()));
+    let locks = Arc::new(Mutex::new(initial_locks));
+    let shutdown = Arc::new(AtomicBool::new(false));
 
-    let state_task = state.clone();
-    let handle = tokio::task::spawn_blocking(move || loop {
+    // the markers used for anything, all others are ignored
+    let interested_in: Vec<String> = facade_marker_ids.iter()
+        .chain(gantry_marker_ids.iter())
+        .chain(facade_extra_ids.iter())
+        .chain(gantry_extra_ids.iter())
+        .chain(std::iter::once(&agv_marker_id))
+        .cloned()
+        .collect();
 
-        // check and remove stale transformations
-        let now = clock.get_now().expect("could not get ros time");
-        let time = r2r::Clock::to_builtin_time(&now);
-        let sec = time.sec;
+    let marker_state_task = marker_state.clone();
+    let state_task = state.clone();
+    let locks_task = locks.clone();
+    let logger_task = logger.clone();
+    let facade_marker_ids_task = facade_marker_ids.clone();
+    let gantry_marker_ids_task = gantry_marker_ids.clone();
+    let agv_marker_id_task = agv_marker_id.clone();
+    let interested_in_task = interested_in.clone();
+    let frames_task = frames.clone();
+    let target_frame_task = target_frame.clone();
+    let locked_target_frame_task = locked_target_frame.clone();
+    let lock_file_task = lock_file.clone();
+    let facade_locked_frame_task = facade_locked_frame.clone();
+    let gantry_locked_frame_task = gantry_locked_frame.clone();
+    let shutdown_task = shutdown.clone();
+    let stale_timeout_task = stale_timeout.clone();
+    let publish_period = Duration::from_secs_f64(1.0 / publish_rate_hz);
+    let publish_period_ns = publish_period.as_nanos() as i64;
+    let publish_handle = tokio::spawn(async move {
+        let started = Instant::now();
+        // the locked transforms last published on /tf_static
+        let mut last_static_locks = None;
+        let mut csv_rows: u64 = 0;
+        let csv_flush_rows = (publish_rate_hz.round() as u64).max(1);
+        // start of the current window of the detection rates
+        let mut rates_since_ns: Option<i64> = None;
+        let mut marker_lock_timing = LockTiming::new("publish loop, markers");
+        let mut state_lock_timing = LockTiming::new("publish loop, state");
+        let mut interval = tokio::time::interval(publish_period);
+        loop {
+            interval.tick().await;
 
-        {
-            let mut state = state_task.lock().unwrap();
-            if state.marker_0.as_ref().map(|t| (sec - t.header.stamp.sec) > 5).unwrap_or(false) {
-                state.marker_0 = None;
-                println!("stale marker 0, removing");
+            if shutdown_task.load(Ordering::SeqCst) {
+                // let downstream know that the estimates are gone.
+                publish(&ok_pub, &Bool { data: false }, &logger_task);
+                publish(&agv_ok_pub, &Bool { data: false }, &logger_task);
+                break;
             }
-            if state.marker_1.as_ref().map(|t| (sec - t.header.stamp.sec) > 5).unwrap_or(false) {
-                state.marker_1 = None;
-                println!("stale marker 1, removing");
+
+            // check and remove stale transformations on the same tick as publishing
+            let now = clock.now(&logger_task);
+            let time = r2r::Clock::to_builtin_time(&now);
+            let now_ns = now.as_nanos() as i64;
+            let stale_timeout_ns = stale_timeout_task.load(Ordering::SeqCst);
+
+            // update the state and take what is published from it under short locks,
+            // the publishing itself happens after they have been released. first the
+            // markers, then what is derived from them, see `MarkerState`.
+            let (status, marker_rates, events, input_lost, agv_live, facade_gone, gantry_gone,
+                 facade_markers, gantry_markers, facade_markers_jitter, gantry_markers_jitter,
+                 agv_jitter) = {
+                let mut marker_state = lock_timed(&marker_state_task, "markers",
+                                                  &mut marker_lock_timing, &logger_task);
+                let marker_state = &mut *marker_state;
+                let stale: Vec<String> = marker_state.markers.iter()
+                    .filter(|(_, t)| is_stale(Some(t), now_ns, stale_timeout_ns))
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                for id in stale {
+                    marker_event(&mut marker_state.marker_events, &id, "stale", now_ns,
+                                 &logger_task);
+                    marker_state.markers.remove(&id);
+                    marker_state.marker_filters.remove(&id);
+                    marker_state.observations.retain(|(_, marker), _| *marker != id);
+                }
+                let input_lost = input_timeout > Duration::ZERO &&
+                    marker_state.last_input.unwrap_or(started).elapsed() > input_timeout;
+
+                // the derived transforms are only recomputed when their own markers
+                // arrive, so they are dropped below when one of their markers is gone,
+                // or both with the single marker fallback.
+                let markers = &marker_state.markers;
+                let agv_live = markers.contains_key(&agv_marker_id_task);
+                let gone = |ids: &[String; 2]| {
+                    let missing = ids.iter().filter(|id| !markers.contains_key(*id)).count();
+                    missing == 2 || (missing == 1 && !single_marker_fallback)
+                };
+                let facade_gone = gone(&facade_marker_ids_task);
+                let gantry_gone = gone(&gantry_marker_ids_task);
+                let owned = |ids: &[String; 2]| pair_markers(markers, ids).map(|t| t.cloned());
+                let facade_markers = owned(&facade_marker_ids_task);
+                let gantry_markers = owned(&gantry_marker_ids_task);
+
+                let filters = &marker_state.marker_filters;
+                let facade_markers_jitter = markers_jitter(filters, &facade_marker_ids_task);
+                let gantry_markers_jitter = markers_jitter(filters, &gantry_marker_ids_task);
+                let agv_jitter = markers_jitter(filters, std::slice::from_ref(&agv_marker_id_task));
+
+                // the freshness of every tracked marker
+                let status: Vec<DiagnosticStatus> = facade_marker_ids_task.iter()
+                    .chain(gantry_marker_ids_task.iter())
+                    .chain(std::iter::once(&agv_marker_id_task))
+                    .map(|id| marker_diagnostic(id, markers.get(id), now_ns, stale_timeout_ns))
+                    .collect();
+
+                // detections per second of every tracked marker over the last
+                // window of about a second. restarts when the time jumps back.
+                let marker_rates = match rates_since_ns {
+                    Some(since) if now_ns >= since && now_ns - since < 1_000_000_000 => None,
+                    Some(since) if now_ns >= since => {
+                        let secs = (now_ns - since) as f64 / 1e9;
+                        let counts = std::mem::take(&mut marker_state.marker_counts);
+                        let rates: serde_json::Map<String, serde_json::Value> =
+                            interested_in_task.iter()
+                                .map(|id| {
+                                    let count = counts.get(id).copied().unwrap_or(0);
+                                    (id.clone(), serde_json::json!(count as f64 / secs))
+                                })
+                                .collect();
+                        rates_since_ns = Some(now_ns);
+                        Some(serde_json::Value::Object(rates).to_string())
+                    }
+                    _ => {
+                        marker_state.marker_counts.clear();
+                        rates_since_ns = Some(now_ns);
+                        None
+                    }
+                };
+
+                let events = std::mem::take(&mut marker_state.marker_events);
+
+                (status, marker_rates, events, input_lost, agv_live, facade_gone, gantry_gone,
+                 facade_markers, gantry_markers, facade_markers_jitter, gantry_markers_jitter,
+                 agv_jitter)
+            };
+
+            let (facade, gantry, agv, locked_facade, locked_gantry, facade_jitter,
+                 gantry_jitter, confidences, velocity, agv_velocity, agv_odom, ok,
+                 auto_locked) = {
+                let mut state = lock_timed(&state_task, "state", &mut state_lock_timing,
+                                           &logger_task);
+                let state = &mut *state;
+                // with the detector gone nothing is updated anymore, so drop the
+                // derived transforms right away instead of waiting for them to go stale.
+                if input_lost && !state.input_lost {
+                    r2r::log_error!(&logger_task, "no marker detections for {:.1} s, \
+                                                   is the aruco detector running?",
+                                    input_timeout.as_secs_f64());
+                } else if !input_lost && state.input_lost {
+                    r2r::log_info!(&logger_task, "marker detections are back");
+                }
+                state.input_lost = input_lost;
+                if input_lost {
+                    state.facade_transform = None;
+                    state.gantry_transform = None;
+                    state.agv_transform = None;
+                }
+
+                if !agv_live {
+                    state.agv_transform = None;
+                }
+                if facade_gone {
+                    state.facade_transform = None;
+                    state.facade_single_offset = None;
+                }
+                if gantry_gone {
+                    state.gantry_transform = None;
+                    state.gantry_single_offset = None;
+                }
+                // held transforms are dropped once they have not been updated for
+                // the hold duration, even if their markers are not stale yet.
+                if hold_ns > 0 {
+                    let derived = [&mut state.facade_transform, &mut state.gantry_transform,
+                                   &mut state.agv_transform];
+                    for t in derived {
+                        if is_stale(t.as_ref(), now_ns, hold_ns) {
+                            if let Some(t) = t.take() {
+                                r2r::log_warn!(&logger_task, "{} was held too long, dropping",
+                                               t.child_frame_id);
+                            }
+                        }
+                    }
+                }
+
+                // lock onto the facade and gantry once they have settled, unless they
+                // are locked already.
+                let mut locks = lock_locks(&locks_task, &logger_task);
+                let locks = &mut *locks;
+                let mut auto_locked = vec![];
+                if auto_lock {
+                    let structures = [
+                        ("facade", &state.facade_transform, &mut state.facade_stability,
+                         &mut locks.facade),
+                        ("gantry", &state.gantry_transform, &mut state.gantry_stability,
+                         &mut locks.gantry),
+                    ];
+                    for (name, transform, stability, locked) in structures {
+                        let stable = stability.update(transform.as_ref(), now_ns,
+                                                      stability_duration_ns, stability_threshold);
+                        if stable && locked.is_none() {
+                            r2r::log_info!(&logger_task, "{} is stable, auto locking", name);
+                            *locked = transform.clone();
+                            auto_locked.push(name);
+                        }
+                    }
+                    if !auto_locked.is_empty() {
+                        store_locks(&lock_file_task, locks, &logger_task);
+                    }
+                }
+
+                // express everything in the target frames, falling back to the camera frame.
+                let frames = lock_or_recover(&frames_task, "frames", &logger_task);
+                let mut lookup_failed = None;
+                let mut express = |t: &Option<TransformStamped>, target: &str| t.as_ref().map(|t| {
+                    in_target_frame(t, target, &frames, now_ns, stale_timeout_ns)
+                        .unwrap_or_else(|| {
+                            lookup_failed = Some((target.to_string(), t.header.frame_id.clone()));
+                            t.clone()
+                        })
+                });
+                let facade = express(&state.facade_transform, &target_frame_task);
+                let gantry = express(&state.gantry_transform, &target_frame_task);
+                let agv = express(&state.agv_transform, &target_frame_task);
+                let locked_facade = express(&locks.facade, &locked_target_frame_task);
+                let locked_gantry = express(&locks.gantry, &locked_target_frame_task);
+                drop(frames);
+                if let Some((target, frame)) = lookup_failed {
+                    if state.log_throttle.ready("target_frame", Duration::from_secs(5)) {
+                        r2r::log_warn!(&logger_task, "no transform from {} to {}, publishing in {}",
+                                       target, frame, frame);
+                    }
+                }
+
+                let facade_jitter = (facade_markers_jitter,
+                                     state.facade_history.jitter().1.powi(2));
+                let gantry_jitter = (gantry_markers_jitter,
+                                     state.gantry_history.jitter().1.powi(2));
+                let confidences = [
+                    confidence(state.facade_transform.as_ref(), &state.facade_history,
+                               [facade_markers[0].as_ref(), facade_markers[1].as_ref()],
+                               now_ns, &confidence_scales),
+                    confidence(state.gantry_transform.as_ref(), &state.gantry_history,
+                               [gantry_markers[0].as_ref(), gantry_markers[1].as_ref()],
+                               now_ns, &confidence_scales),
+                ];
+
+                match gantry.as_ref() {
+                    Some(t) => state.gantry_velocity.update(t, velocity_smoothing_factor,
+                                                            stale_timeout_ns),
+                    None => state.gantry_velocity.reset(),
+                }
+                let velocity = match state.gantry_velocity.linear {
+                    Some(linear) => Some((linear, state.gantry_velocity.yaw_rate)),
+                    None if publish_zero_velocity => Some((Vector3::new(0.0, 0.0, 0.0), 0.0)),
+                    None => None,
+                };
+
+                // the agv is only published as odometry while it is live, with the
+                // velocity estimated like the gantry one.
+                match agv.as_ref() {
+                    Some(t) => state.agv_velocity.update(t, velocity_smoothing_factor,
+                                                         stale_timeout_ns),
+                    None => state.agv_velocity.reset(),
+                }
+                let agv_odom = match (agv.as_ref(), state.agv_velocity.linear) {
+                    (Some(t), Some(linear)) => {
+                        Some((t.clone(), agv_jitter, linear, state.agv_velocity.yaw_rate))
+                    }
+                    _ => None,
+                };
+                // zero until there are two samples to estimate it from
+                let agv_velocity = agv.as_ref().map(|_| {
+                    (state.agv_velocity.linear.unwrap_or_else(|| Vector3::new(0.0, 0.0, 0.0)),
+                     state.agv_velocity.yaw_rate)
+                });
+
+                // debounced so it does not chatter at the edge of visibility
+                let ok = state.facade_transform.is_some() &&
+                    state.gantry_transform.is_some();
+                let ok = state.measured.update(ok, now_ns, measured_on_ns, measured_off_ns);
+                let ok = ok && !input_lost;
+
+                (facade, gantry, agv, locked_facade, locked_gantry, facade_jitter,
+                 gantry_jitter, confidences, velocity, agv_velocity, agv_odom, ok,
+                 auto_locked)
+            };
+
+            // publish markers coming and going
+            for data in events {
+                publish(&marker_events_pub, &r2r::std_msgs::msg::String { data }, &logger_task);
             }
-            if state.marker_2.as_ref().map(|t| (sec - t.header.stamp.sec) > 5).unwrap_or(false) {
-                state.marker_2 = None;
-                println!("stale marker 2, removing");
+
+            // let operators know when a lock was taken automatically
+            for name in auto_locked {
+                let event = r2r::std_msgs::msg::String { data: format!("auto locked {}", name) };
+                publish(&auto_lock_pub, &event, &logger_task);
             }
-            if state.marker_15.as_ref().map(|t| (sec - t.header.stamp.sec) > 5).unwrap_or(false) {
-                state.marker_15 = None;
-                println!("stale marker 15, removing");
+
+            // publish floating positions to tf. unless everything is stamped with
+            // the measurement, held transforms are restamped so that tf keeps them
+            // alive during short occlusions.
+            let mut transforms = vec![];
+            for t in [&facade, &gantry, &agv].iter().filter_map(|t| t.as_ref()) {
+                let mut t = t.clone();
+                let held = is_held(&t, now_ns, hold_ns, publish_period_ns);
+                if stamp_source == "now" || (held && stamp_source == "mixed") {
+                    t.header.stamp = time.clone();
+                }
+                transforms.push(t);
             }
-            if state.marker_5.as_ref().map(|t| (sec - t.header.stamp.sec) > 5).unwrap_or(false) {
-                state.marker_5 = None;
-                println!("stale marker 5, removing");
+            publish_tf(&tf_pubs, transforms, &logger_task);
+
+            // publish locked positions to tf. static transforms keep the stamp of
+            // the lock, the others are restamped to stay alive in tf unless
+            // everything is stamped with the measurement.
+            let restamp = |mut t: TransformStamped, child_frame_id: &str| {
+                t.child_frame_id = child_frame_id.into();
+                if !locked_tf_static && stamp_source != "measurement" {
+                    t.header.stamp = time.clone();
+                }
+                t
+            };
+            let locked_facade = locked_facade.map(|t| restamp(t, &facade_locked_frame_task));
+            let locked_gantry = locked_gantry.map(|t| restamp(t, &gantry_locked_frame_task));
+            let transforms: Vec<TransformStamped> = locked_facade.iter()
+                .chain(locked_gantry.iter())
+                .cloned()
+                .collect();
+            let locked_tf_pubs = match locked_tf_pub.as_ref() {
+                Some(locked_tf_pub) => std::slice::from_ref(locked_tf_pub),
+                None => &tf_pubs,
+            };
+            if !locked_tf_static {
+                publish_tf(locked_tf_pubs, transforms, &logger_task);
+            } else if last_static_locks.as_ref() != Some(&transforms) {
+                last_static_locks = Some(transforms.clone());
+                publish_tf(locked_tf_pubs, transforms, &logger_task);
             }
-        }
 
-        // publish results.
-        {
-            let state = state_task.lock().unwrap();
+            // publish rviz markers, live estimates in yellow and locked ones in green.
+            let live = ColorRGBA { r: 1.0, g: 1.0, b: 0.0, a: 0.8 };
+            let locked = ColorRGBA { r: 0.0, g: 1.0, b: 0.0, a: 0.8 };
+            let mut markers = vec![];
+            markers.extend(visualization_markers("facade", facade.as_ref(), live.clone()));
+            markers.extend(visualization_markers("gantry", gantry.as_ref(), live.clone()));
+            markers.extend(visualization_markers("agv", agv.as_ref(), live));
+            markers.extend(visualization_markers("facade_locked", locked_facade.as_ref(),
+                                                 locked.clone()));
+            markers.extend(visualization_markers("gantry_locked", locked_gantry.as_ref(),
+                                                 locked));
+            publish(&markers_pub, &MarkerArray { markers }, &logger_task);
 
-            // publish floating positions to tf
-            let mut transforms = vec![];
-            if let Some(t) = state.facade_transform.as_ref() {
-                transforms.push(t.clone());
+            // publish poses with covariance
+            let age_secs = |t: &TransformStamped| {
+                (now_ns - stamp_to_nanos(&t.header.stamp)) as f64 / 1e9
+            };
+            if let Some(t) = facade.as_ref() {
+                let msg = pose_with_covariance(t, facade_jitter.0, facade_jitter.1, age_secs(t),
+                                               pose_base_variance, pose_variance_per_sec);
+                publish(&facade_pose_pub, &msg, &logger_task);
+            }
+            if let Some(t) = gantry.as_ref() {
+                let msg = pose_with_covariance(t, gantry_jitter.0, gantry_jitter.1, age_secs(t),
+                                               pose_base_variance, pose_variance_per_sec);
+                publish(&gantry_pose_pub, &msg, &logger_task);
             }
-            if let Some(t) = state.gantry_transform.as_ref() {
-                transforms.push(t.clone());
+
+            // publish the distance between the gantry and the facade
+            match (gantry.as_ref(), facade.as_ref()) {
+                (Some(gantry), Some(facade)) => {
+                    let distance = Float64 { data: planar_distance(gantry, facade) };
+                    publish(&distance_pub, &distance, &logger_task);
+                }
+                _ if publish_nan_distance => {
+                    publish(&distance_pub, &Float64 { data: f64::NAN }, &logger_task);
+                }
+                _ => (),
             }
-            if let Some(t) = state.agv_transform.as_ref() {
-                transforms.push(t.clone());
+
+            // publish the freshness of every tracked marker, also as a single line
+            // like "aruco_0 live 0.050 s, aruco_1 missing" for simple dashboards
+            let marker_status: Vec<String> = status.iter()
+                .map(|s| match s.values.iter().find(|v| v.key == "age_secs") {
+                    Some(age) if !age.value.is_empty() => {
+                        format!("{} {} {} s", s.hardware_id, s.message, age.value)
+                    }
+                    _ => format!("{} {}", s.hardware_id, s.message),
+                })
+                .collect();
+            let marker_status = r2r::std_msgs::msg::String { data: marker_status.join(", ") };
+            publish(&marker_status_pub, &marker_status, &logger_task);
+            if let Some(rates) = marker_rates {
+                let msg = r2r::std_msgs::msg::String { data: rates };
+                publish(&marker_rates_pub, &msg, &logger_task);
             }
-            let tf_msg = TFMessage {
-                transforms,
+            let diagnostics = DiagnosticArray {
+                header: r2r::std_msgs::msg::Header { stamp: time.clone(), frame_id: "".into() },
+                status,
             };
-            tf_pub.publish(&tf_msg).expect("could not publish");
-            tf_pub2.publish(&tf_msg).expect("could not publish");
+            publish(&diagnostics_pub, &diagnostics, &logger_task);
 
-            // publish locked positions to tf.
-            let mut transforms = vec![];
-            if let Some(t) = state.locked_facade_transform.as_ref() {
-                let mut t = t.clone();
-                t.child_frame_id = "facade_locked".into();
-                t.header.stamp = time.clone();
-                transforms.push(t);
+            // publish the gantry relative to the facade, and how square it is to it
+            if let (Some(facade), Some(gantry)) = (facade.as_ref(), gantry.as_ref()) {
+                let mut relative = compose(&invert(facade), gantry);
+                if stamp_source == "now" {
+                    relative.header.stamp = time.clone();
+                }
+                let yaw = Float64 { data: transform_yaw(&relative) };
+                publish(&gantry_in_facade_pub, &relative, &logger_task);
+                publish(&gantry_in_facade_yaw_pub, &yaw, &logger_task);
             }
-            if let Some(t) = state.locked_gantry_transform.as_ref() {
-                let mut t = t.clone();
-                t.child_frame_id = "gantry_locked".into();
-                t.header.stamp = time.clone();
-                transforms.push(t);
+
+            // publish the headings for display. the yaw is the direction of the x
+            // axis in the frame the transforms are expressed in, counter clockwise
+            // from its x axis when seen from above, in [-180, 180].
+            let yaws = [(&facade_yaw_pub, facade.as_ref()), (&gantry_yaw_pub, gantry.as_ref())];
+            for (publisher, t) in yaws {
+                if let Some(t) = t {
+                    let yaw = Float64 { data: transform_yaw(t).to_degrees() };
+                    publish(publisher, &yaw, &logger_task);
+                }
+            }
+
+            // publish the confidence in the live estimates, 0 while they are absent
+            let confidence_pubs = [&facade_confidence_pub, &gantry_confidence_pub];
+            for (publisher, confidence) in confidence_pubs.iter().zip(confidences) {
+                publish(publisher, &Float64 { data: confidence }, &logger_task);
+            }
+
+            // publish how far the live estimates have drifted from their locks. only
+            // when both are published in the same frame, the difference means nothing
+            // otherwise.
+            let alignments = [
+                (&facade_alignment_pub, facade.as_ref(), locked_facade.as_ref()),
+                (&gantry_alignment_pub, gantry.as_ref(), locked_gantry.as_ref()),
+            ];
+            for (publisher, live, locked) in alignments {
+                if let (Some(live), Some(locked)) = (live, locked) {
+                    if live.header.frame_id == locked.header.frame_id {
+                        publish(publisher, &alignment_error(live, locked), &logger_task);
+                    }
+                }
+            }
+
+            // publish the gantry and agv velocities
+            let velocities = [(&gantry_velocity_pub, velocity, gantry.as_ref()),
+                              (&agv_velocity_pub, agv_velocity, agv.as_ref())];
+            for (publisher, velocity, t) in velocities {
+                if let Some((linear, yaw_rate)) = velocity {
+                    let frame_id = t.map(|t| t.header.frame_id.clone()).unwrap_or_default();
+                    let mut twist = Twist::default();
+                    twist.linear.x = linear.x;
+                    twist.linear.y = linear.y;
+                    twist.linear.z = linear.z;
+                    twist.angular.z = yaw_rate;
+                    let msg = TwistStamped {
+                        header: r2r::std_msgs::msg::Header { stamp: time.clone(), frame_id },
+                        twist,
+                    };
+                    publish(publisher, &msg, &logger_task);
+                }
+            }
+
+            // publish the agv odometry
+            if let Some((t, jitter, linear, yaw_rate)) = agv_odom {
+                let pose = pose_with_covariance(&t, jitter, 0.0, age_secs(&t),
+                                                pose_base_variance, pose_variance_per_sec).pose;
+                let msg = odometry(&t, pose, linear, yaw_rate, twist_variance);
+                publish(&agv_odom_pub, &msg, &logger_task);
             }
-            let tf_msg = TFMessage {
-                transforms,
-            };
-            tf_pub.publish(&tf_msg).expect("could not publish");
-            tf_pub2.publish(&tf_msg).expect("could not publish");
 
             // publish to sp
-            let ok = state.facade_transform.is_some() &&
-                state.gantry_transform.is_some();
-            let ok = Bool { data: ok };
-            ok_pub.publish(&ok).expect("could not publish");
+            publish(&ok_pub, &Bool { data: ok }, &logger_task);
+            publish(&agv_ok_pub, &Bool { data: agv.is_some() }, &logger_task);
+            if input_timeout > Duration::ZERO {
+                publish(&detector_alive_pub, &Bool { data: !input_lost }, &logger_task);
+            }
+
+            // log the estimates, flushing about once a second so that the data
+            // survives a crash. the log is given up on the first error.
+            if let Some(writer) = csv_log.as_mut() {
+                csv_rows += 1;
+                let row = csv_row(now_ns, &[&facade, &gantry, &agv]);
+                let result = writeln!(writer, "{}", row).and_then(|_| {
+                    if csv_rows < csv_flush_rows {
+                        return Ok(());
+                    }
+                    csv_rows = 0;
+                    writer.flush()
+                });
+                if let Err(e) = result {
+                    r2r::log_error!(&logger_task, "could not write the csv log, stopping: {}", e);
+                    csv_log = None;
+                }
+            }
         }
+    });
 
-        node.spin_once(std::time::Duration::from_millis(100));
+    // spin ros in its own thread, decoupled from the publishing rate.
+    let shutdown_task = shutdown.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+        while !shutdown_task.load(Ordering::SeqCst) {
+            node.spin_once(Duration::from_millis(100));
+        }
     });
 
 
+    let mut trigger_clock = TimeSource::create(&sim_time)?;
+    let stale_timeout_task = stale_timeout.clone();
+    let marker_state_task = marker_state.clone();
     let state_task = state.clone();
-    tokio::spawn(async move {
+    let locks_task = locks.clone();
+    let logger_task = logger.clone();
+    let lock_file_task = lock_file.clone();
+    let facade_marker_ids_task = facade_marker_ids.clone();
+    let gantry_marker_ids_task = gantry_marker_ids.clone();
+    let trigger_handle = tokio::spawn(async move {
         loop {
             if let Some(req) = trigger_srv.next().await {
-                let mut state = state_task.lock().unwrap();
-                state.locked_gantry_transform = state.gantry_transform.clone();
-                state.locked_facade_transform = state.facade_transform.clone();
+                let now = trigger_clock.now(&logger_task);
+                let now_ns = now.as_nanos() as i64;
+                // refuse to lock onto transforms computed from markers older than this.
+                let lock_max_age_ns = stale_timeout_task.load(Ordering::SeqCst) / 2;
+                let (unavailable, quality): (Vec<String>, Vec<String>) = {
+                    let marker_state = lock_markers(&marker_state_task, &logger_task);
+                    let state = lock_state(&state_task, &logger_task);
+                    let gantry_markers = pair_markers(&marker_state.markers,
+                                                      &gantry_marker_ids_task);
+                    let facade_markers = pair_markers(&marker_state.markers,
+                                                      &facade_marker_ids_task);
+                    let unavailable = vec![
+                        lock_unavailable("gantry", &state.gantry_transform, gantry_markers,
+                                         now_ns, lock_max_age_ns),
+                        lock_unavailable("facade", &state.facade_transform, facade_markers,
+                                         now_ns, lock_max_age_ns),
+                    ].into_iter().flatten().collect();
+                    let quality = vec![
+                        lock_quality("gantry", &state.gantry_history, gantry_markers, now_ns),
+                        lock_quality("facade", &state.facade_history, facade_markers, now_ns),
+                    ];
+                    (unavailable, quality)
+                };
+                if !unavailable.is_empty() {
+                    let response = Trigger::Response {
+                        success: false,
+                        message: format!("not locking: {}", unavailable.join(", ")),
+                    };
+                    if let Err(e) = req.respond(response) {
+                        r2r::log_error!(&logger_task, "could not send response: {}", e);
+                    }
+                    continue;
+                }
+
+                // lock onto the average of a number of samples rather than a single one.
+                let response = match collect_samples(&state_task, calibration_samples,
+                                                     publish_period, &logger_task).await {
+                    Ok((gantry_samples, facade_samples)) => {
+                        let (gantry, gantry_t, gantry_r) = average_transforms(&gantry_samples);
+                        let (facade, facade_t, facade_r) = average_transforms(&facade_samples);
+                        let poses = format!("{}, {}", describe_lock("gantry", &gantry),
+                                            describe_lock("facade", &facade));
+                        let mut locks = lock_locks(&locks_task, &logger_task);
+                        locks.gantry = Some(gantry);
+                        locks.facade = Some(facade);
+                        store_locks(&lock_file_task, &locks, &logger_task);
+                        Trigger::Response {
+                            success: true,
+                            message: format!("locked {} from {} samples, \
+                                              spread gantry: {:.4} m {:.4} rad, \
+                                              facade: {:.4} m {:.4} rad; {}",
+                                             poses, calibration_samples, gantry_t, gantry_r,
+                                             facade_t, facade_r, quality.join("; ")),
+                        }
+                    }
+                    Err(e) => Trigger::Response {
+                        success: false,
+                        message: format!("not locking: {}", e),
+                    },
+                };
+
+                if let Err(e) = req.respond(response) {
+                    r2r::log_error!(&logger_task, "could not send response: {}", e);
+                }
+            }
+        }
+    });
+
+    // lock_facade and lock_gantry each lock only their own structure.
+    let mut lock_handles = vec![];
+    for (name, mut srv) in [("facade", lock_facade_srv), ("gantry", lock_gantry_srv)] {
+        let mut lock_clock = TimeSource::create(&sim_time)?;
+        let stale_timeout_task = stale_timeout.clone();
+        let marker_state_task = marker_state.clone();
+        let state_task = state.clone();
+        let locks_task = locks.clone();
+        let logger_task = logger.clone();
+        let lock_file_task = lock_file.clone();
+        let facade_marker_ids_task = facade_marker_ids.clone();
+        let gantry_marker_ids_task = gantry_marker_ids.clone();
+        lock_handles.push(tokio::spawn(async move {
+            loop {
+                if let Some(req) = srv.next().await {
+                    let now = lock_clock.now(&logger_task);
+                    let now_ns = now.as_nanos() as i64;
+                    let lock_max_age_ns = stale_timeout_task.load(Ordering::SeqCst) / 2;
+                    let marker_state = lock_markers(&marker_state_task, &logger_task);
+                    let state = lock_state(&state_task, &logger_task);
+                    let mut locks = lock_locks(&locks_task, &logger_task);
+
+                    let (transform, ids, history) = match name {
+                        "facade" => (&state.facade_transform, &facade_marker_ids_task,
+                                     &state.facade_history),
+                        _ => (&state.gantry_transform, &gantry_marker_ids_task,
+                              &state.gantry_history),
+                    };
+                    let markers = pair_markers(&marker_state.markers, ids);
+                    let response = match lock_unavailable(name, transform, markers,
+                                                           now_ns, lock_max_age_ns) {
+                        None => {
+                            let quality = lock_quality(name, history, markers, now_ns);
+                            let pose = transform.as_ref().map(|t| describe_lock(name, t))
+                                .unwrap_or_default();
+                            match name {
+                                "facade" => locks.facade = transform.clone(),
+                                _ => locks.gantry = transform.clone(),
+                            }
+                            store_locks(&lock_file_task, &locks, &logger_task);
+                            Trigger::Response {
+                                success: true,
+                                message: format!("locked {}; {}", pose, quality),
+                            }
+                        }
+                        Some(reason) => Trigger::Response {
+                            success: false,
+                            message: format!("not locking: {}", reason),
+                        },
+                    };
+
+                    if let Err(e) = req.respond(response) {
+                        r2r::log_error!(&logger_task, "could not send response: {}", e);
+                    }
+                }
+            }
+        }));
+    }
+
+    let mut estimates_clock = TimeSource::create(&sim_time)?;
+    let state_task = state.clone();
+    let logger_task = logger.clone();
+    let get_estimates_handle = tokio::spawn(async move {
+        loop {
+            if let Some(req) = get_estimates_srv.next().await {
+                let now = estimates_clock.now(&logger_task);
+                let now_ns = now.as_nanos() as i64;
+                let state = lock_state(&state_task, &logger_task);
+                let held = |t: &Option<TransformStamped>| t.as_ref()
+                    .map(|t| is_held(t, now_ns, hold_ns, publish_period_ns))
+                    .unwrap_or(false);
+                let mut estimates = serde_json::json!({
+                    "facade": estimate_json(&state.facade_transform,
+                                            held(&state.facade_transform)),
+                    "gantry": estimate_json(&state.gantry_transform,
+                                            held(&state.gantry_transform)),
+                    "agv": estimate_json(&state.agv_transform, held(&state.agv_transform)),
+                });
+                // derived from one marker only, with the yaw held
+                estimates["facade"]["single_marker"] = state.facade_single_offset.is_some().into();
+                estimates["gantry"]["single_marker"] = state.gantry_single_offset.is_some().into();
 
-                let message = format!("gantry: {}, facade: {}",
-                                      state.locked_gantry_transform.is_some(),
-                                      state.locked_facade_transform.is_some(),
-                );
                 let response = Trigger::Response {
                     success: true,
-                    message,
+                    message: estimates.to_string(),
                 };
 
-                req.respond(response).expect("could not send response");
+                if let Err(e) = req.respond(response) {
+                    r2r::log_error!(&logger_task, "could not send response: {}", e);
+                }
             }
         }
     });
 
-    let interested_in = &["aruco_0", "aruco_1", "aruco_2", "aruco_15", "aruco_5"];
-    sub.for_each(|msg| {
-        if !interested_in.contains(&msg.child_frame_id.as_str()) {
-            return future::ready(());
+    let marker_state_task = marker_state.clone();
+    let state_task = state.clone();
+    let locks_task = locks.clone();
+    let logger_task = logger.clone();
+    let lock_file_task = lock_file.clone();
+    let reset_handle = tokio::spawn(async move {
+        loop {
+            if let Some(req) = reset_srv.next().await {
+                let mut marker_state = lock_markers(&marker_state_task, &logger_task);
+                let mut state = lock_state(&state_task, &logger_task);
+                let mut locks = lock_locks(&locks_task, &logger_task);
+                marker_state.reset();
+                state.reset();
+                if reset_clears_locks {
+                    *locks = Locks::default();
+                    store_locks(&lock_file_task, &locks, &logger_task);
+                }
+                r2r::log_info!(&logger_task, "reset all markers");
+
+                let response = Trigger::Response {
+                    success: true,
+                    message: format!("reset all markers, locks {}",
+                                     if reset_clears_locks { "cleared" } else { "kept" }),
+                };
+
+                if let Err(e) = req.respond(response) {
+                    r2r::log_error!(&logger_task, "could not send response: {}", e);
+                }
+            }
         }
-        // println!("new msg: {:?}", msg);
-        // if !marker_ok(&msg) {
-        //     println!("bad marker: {}", msg.child_frame_id);
-        //     return future::ready(());
-        // }
-        if msg.child_frame_id == "aruco_0" {
-            update_or_set(msg.clone(), &mut state.lock().unwrap().marker_0);
+    });
+
+    let locks_task = locks.clone();
+    let logger_task = logger.clone();
+    let lock_file_task = lock_file.clone();
+    let clear_lock_handle = tokio::spawn(async move {
+        loop {
+            if let Some(req) = clear_lock_srv.next().await {
+                let mut locks = lock_locks(&locks_task, &logger_task);
+                let message = format!("cleared gantry: {}, cleared facade: {}",
+                                      locks.gantry.is_some(), locks.facade.is_some(),
+                );
+                *locks = Locks::default();
+                store_locks(&lock_file_task, &locks, &logger_task);
+
+                let response = Trigger::Response {
+                    success: true,
+                    message,
+                };
+
+                if let Err(e) = req.respond(response) {
+                    r2r::log_error!(&logger_task, "could not send response: {}", e);
+                }
+            }
         }
-        if msg.child_frame_id == "aruco_1" {
-            update_or_set(msg.clone(), &mut state.lock().unwrap().marker_1);
+    });
+
+    if simulate {
+        let ids = [facade_marker_ids[0].clone(), facade_marker_ids[1].clone(),
+                   gantry_marker_ids[0].clone(), gantry_marker_ids[1].clone(),
+                   agv_marker_id.clone()];
+        let clock = TimeSource::create(&sim_time)?;
+        let interval = tokio::time::interval(Duration::from_secs_f64(1.0 / simulate_rate_hz));
+        let logger = logger.clone();
+        let simulated = stream::unfold((clock, interval), move |(mut clock, mut interval)| {
+            let ids = ids.clone();
+            let logger = logger.clone();
+            async move {
+                interval.tick().await;
+                let markers = simulated_markers(&ids, &simulated_centers, clock.now(&logger));
+                Some((markers, (clock, interval)))
+            }
+        });
+        let simulated = simulated.map(|markers| ("simulate".to_string(), markers));
+        inputs.push(simulated.boxed());
+    }
+    // fuse only when detections come from more than one of the active inputs, not
+    // when several aruco_topics are configured but not subscribed.
+    let fuse = inputs.len() > 1;
+    let sub = stream::select_all(inputs);
+
+    let mut markers_clock = TimeSource::create(&sim_time)?;
+    let marker_params = MarkerParams {
+        interested_in,
+        min_range: min_marker_range,
+        max_range: max_marker_range,
+        fuse,
+        fusion_window_ns,
+        target_frame,
+        filter: filter_params,
+        facade_ids: facade_marker_ids,
+        gantry_ids: gantry_marker_ids,
+        facade_extra_ids,
+        gantry_extra_ids,
+        facade_pair,
+        gantry_pair,
+        quality_window,
+        agv_id: agv_marker_id,
+        agv_frame,
+    };
+    let mut timings = [LockTiming::new("marker subscriber, markers"),
+                       LockTiming::new("marker subscriber, state")];
+    let process_markers = sub.for_each(|(source, transforms)| {
+        // any message shows that the detector is alive, also one without detections
+        lock_markers(&marker_state, &logger).last_input = Some(Instant::now());
+        let now_ns = markers_clock.now(&logger).as_nanos() as i64;
+        for msg in transforms {
+            process_marker(&marker_params, &marker_state, &state, &frames, &mut timings,
+                           source.clone(), msg, now_ns, stale_timeout.load(Ordering::SeqCst),
+                           &logger);
         }
+        future::ready(())
+    });
+
+    tokio::select! {
+        _ = process_markers => (),
+        _ = shutdown_signal() => r2r::log_info!(&logger, "shutting down"),
+    }
 
-        {
-            let mut state = state.lock().unwrap();
-            if state.marker_0.is_some() && state.marker_1.is_some() {
-                let marker0 = state.marker_0.as_ref().unwrap().transform.clone();
-                let marker1 = state.marker_1.as_ref().unwrap().transform.clone();
+    // stop publishing (after a final measured=false), the services and the spinning.
+    shutdown.store(true, Ordering::SeqCst);
+    publish_handle.await?;
+    trigger_handle.abort();
+    clear_lock_handle.abort();
+    get_estimates_handle.abort();
+    reset_handle.abort();
+    for handle in lock_handles.into_iter().chain(listener_handles) {
+        handle.abort();
+    }
+    handle.await?;
 
-                let diff_x = marker1.translation.x - marker0.translation.x;
-                let diff_y = marker1.translation.y - marker0.translation.y;
-                let yaw = diff_y.atan2(diff_x);
+    Ok(())
+}
 
-                let mut new_transform = state.marker_1.as_ref().unwrap().clone();
-                new_transform.child_frame_id = "facade_aruco".into();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let rot = Quaternion::from(Euler {
-                    x: Rad(0.0),
-                    y: Rad(0.0),
-                    z: Rad(yaw),
-                });
+    fn transform(x: f64, y: f64, z: f64) -> TransformStamped {
+        let mut t = TransformStamped::default();
+        t.transform.translation.x = x;
+        t.transform.translation.y = y;
+        t.transform.translation.z = z;
+        t.transform.rotation.w = 1.0;
+        t
+    }
 
-                let rot2 = Quaternion::from(Euler {
-                    x: Deg(180.0),
-                    y: Deg(0.0),
-                    z: Deg(0.0),
-                });
+    fn uniform(factor: f64) -> Smoothing {
+        Smoothing { translation: [factor; 3], rotation: factor }
+    }
 
-                // set yaw and rotate around x to turn upside down.
-                let new_q = rot * rot2;
+    fn translation(t: &TransformStamped) -> (f64, f64, f64) {
+        let v = &t.transform.translation;
+        (v.x, v.y, v.z)
+    }
 
-                new_transform.transform.rotation.w = new_q.s;
-                new_transform.transform.rotation.x = new_q.v.x;
-                new_transform.transform.rotation.y = new_q.v.y;
-                new_transform.transform.rotation.z = new_q.v.z;
+    #[test]
+    fn confidence_counts_markers_and_falls_off_with_age() {
+        let scales = ConfidenceScales { age_secs: 1.0, position_jitter: 0.01, yaw_jitter: 0.02 };
+        let history = TransformHistory::default();
+        let t = transform(0.0, 0.0, 0.0);
+        let mut old = transform(1.0, 0.0, 0.0);
+        old.header.stamp.sec = -1;
+        assert_eq!(confidence(None, &history, [Some(&t), Some(&t)], 0, &scales), 0.0);
+        assert!((confidence(Some(&t), &history, [Some(&t), Some(&t)], 0, &scales) - 1.0).abs()
+                < 1e-9);
+        assert!((confidence(Some(&t), &history, [Some(&t), None], 0, &scales) - 0.5).abs() < 1e-9);
+        let aged = confidence(Some(&t), &history, [Some(&old), Some(&old)], 0, &scales);
+        assert!((aged - (-1.0f64).exp()).abs() < 1e-9);
+    }
 
-                // set hardcoded height
-                new_transform.transform.translation.z = 3.57;
+    #[tokio::test]
+    async fn lock_state_recovers_from_a_panicking_task() {
+        let state = Arc::new(Mutex::new(State::default()));
+        let state_task = state.clone();
+        let panicked = tokio::spawn(async move {
+            let mut state = lock_state(&state_task, "test");
+            state.agv_transform = Some(transform(1.0, 2.0, 3.0));
+            panic!("panicking with the state locked");
+        }).await;
+        assert!(panicked.is_err());
+        assert!(state.is_poisoned());
 
-                state.facade_transform = Some(new_transform);
-            } else {
-                state.facade_transform = None;
+        let state_task = state.clone();
+        let agv = tokio::spawn(async move {
+            let state = lock_state(&state_task, "test");
+            state.agv_transform.clone()
+        }).await.expect("the state could not be locked after the panic");
+        assert_eq!(translation(&agv.expect("the agv transform was lost")), (1.0, 2.0, 3.0));
+        assert!(!state.is_poisoned());
+    }
+
+    fn filter_params() -> FilterParams {
+        FilterParams {
+            smoothing: uniform(10.0),
+            marker_smoothing: HashMap::new(),
+            time_constant: None,
+            median_window: 1,
+            max_jump_meters: 0.5,
+            jump_accept_count: 3,
+            max_tilt_xy: 0.2,
+            min_up_z: 0.9,
+            max_future_skew_ns: 0,
+            frozen_epsilon: 1e-6,
+            frozen_timeout_ns: 0,
+            frozen_is_stale: false,
+        }
+    }
+
+    /// samples of a single marker fed through `update_or_set` with `filter_params`,
+    /// one second apart.
+    struct MarkerFeed {
+        params: FilterParams,
+        markers: HashMap<String, TransformStamped>,
+        filter: MarkerFilter,
+        throttle: LogThrottle,
+        events: Vec<String>,
+        sec: i32,
+    }
+
+    impl MarkerFeed {
+        fn new() -> MarkerFeed {
+            MarkerFeed {
+                params: filter_params(),
+                markers: HashMap::new(),
+                filter: MarkerFilter::default(),
+                throttle: LogThrottle::default(),
+                events: vec![],
+                sec: 0,
             }
         }
 
-        if msg.child_frame_id == "aruco_2" {
-            update_or_set(msg.clone(), &mut state.lock().unwrap().marker_2);
+        /// feed `t` as the next sample of aruco_0.
+        fn feed(&mut self, mut t: TransformStamped) {
+            self.sec += 1;
+            t.child_frame_id = "aruco_0".into();
+            t.header.stamp.sec = self.sec;
+            update_or_set(t, &mut self.markers, &mut self.filter, &self.params, 0,
+                          &mut self.throttle, &mut self.events, "test");
         }
 
-        if msg.child_frame_id == "aruco_15" {
-            update_or_set(msg.clone(), &mut state.lock().unwrap().marker_15);
+        /// feed a sample at `x` and return the x of the marker after it.
+        fn feed_x(&mut self, x: f64) -> f64 {
+            self.feed(transform(x, 0.0, 1.0));
+            self.markers["aruco_0"].transform.translation.x
         }
+    }
 
-        {
-            let mut state = state.lock().unwrap();
-            if state.marker_15.is_some() && state.marker_2.is_some() {
-                let marker15 = &state.marker_15.as_ref().unwrap().transform;
-                let marker2 = &state.marker_2.as_ref().unwrap().transform;
+    #[test]
+    fn update_or_set_rejects_a_single_outlier_and_follows_a_relocation() {
+        let mut feed = MarkerFeed::new();
+        for _ in 0..10 {
+            feed.feed_x(1.0);
+        }
+        assert!((feed.feed_x(5.0) - 1.0).abs() < 1e-9);
+        assert!((feed.feed_x(1.0) - 1.0).abs() < 1e-9);
 
-                let diff_x = marker15.translation.x - marker2.translation.x;
-                let diff_y = marker15.translation.y - marker2.translation.y;
-                let yaw = diff_y.atan2(diff_x);
+        // spikes that do not agree with each other never add up to a relocation
+        for x in [5.0, -3.0, 5.0, -3.0, 5.0, -3.0].iter() {
+            assert!((feed.feed_x(*x) - 1.0).abs() < 1e-9);
+        }
 
-                // gantry position is marker15 position with this new rotation.
-                let mut gantry_transform = state.marker_15.as_ref().unwrap().clone();
-                gantry_transform.child_frame_id = "gantry_aruco".into();
+        // enough consecutive samples at the new position are taken as they are
+        for _ in 1..feed.params.jump_accept_count {
+            assert!((feed.feed_x(5.0) - 1.0).abs() < 1e-9);
+        }
+        assert!((feed.feed_x(5.0) - 5.0).abs() < 1e-9);
+    }
 
-                let rot = Quaternion::from(Euler {
-                    x: Rad(0.0),
-                    y: Rad(0.0),
-                    z: Rad(yaw),
-                });
+    #[test]
+    fn update_or_set_normalizes_rotations_and_rejects_zero_ones() {
+        let mut feed = MarkerFeed::new();
+        let mut zero = transform(1.0, 0.0, 1.0);
+        zero.transform.rotation.w = 0.0;
+        feed.feed(zero.clone());
+        assert!(feed.markers.is_empty());
+        let mut scaled = zero;
+        scaled.transform.rotation.w = 1.02;
+        feed.feed(scaled);
+        let norm = quaternion_from_msg(&feed.markers["aruco_0"].transform.rotation).magnitude();
+        assert!((norm - 1.0).abs() < 1e-12);
+    }
 
-                let rot2 = Quaternion::from(Euler {
-                    x: Deg(180.0),
-                    y: Deg(0.0),
-                    z: Deg(0.0),
-                });
+    #[test]
+    fn stale_check_uses_nanoseconds_across_the_second_boundary() {
+        let mut t = transform(0.0, 0.0, 0.0);
+        t.header.stamp.sec = 9;
+        t.header.stamp.nanosec = 999_999_999;
+        assert_eq!(stamp_to_nanos(&t.header.stamp), 9_999_999_999);
+        // 1 ns later the second has rolled over, 2 ns after that it is stale
+        let now_ns = 10_000_000_000;
+        assert!(!is_stale(Some(&t), now_ns, 1));
+        assert!(!is_stale(Some(&t), now_ns + 1, 2));
+        assert!(is_stale(Some(&t), now_ns + 2, 2));
+        assert!(!is_stale(None, now_ns, 0));
+    }
 
-                let gantry_q = rot * rot2;
+    #[test]
+    fn debounce_ignores_short_dropouts() {
+        let mut measured = Debounce::default();
+        let (on, off) = (200, 500);
+        assert!(!measured.update(true, 0, on, off));
+        assert!(!measured.update(true, 100, on, off));
+        assert!(measured.update(true, 200, on, off));
+        // a single dropped detection does not turn it false
+        assert!(measured.update(false, 300, on, off));
+        assert!(measured.update(true, 400, on, off));
+        assert!(measured.update(false, 500, on, off));
+        assert!(measured.update(false, 900, on, off));
+        assert!(!measured.update(false, 1000, on, off));
+    }
 
-                gantry_transform.transform.rotation.w = gantry_q.s;
-                gantry_transform.transform.rotation.x = gantry_q.v.x;
-                gantry_transform.transform.rotation.y = gantry_q.v.y;
-                gantry_transform.transform.rotation.z = gantry_q.v.z;
+    fn known(parent: &str, child: &str, x: f64, sec: i32, is_static: bool)
+             -> ((String, String), KnownTransform) {
+        let mut transform = transform(x, 0.0, 0.0);
+        transform.header.frame_id = parent.into();
+        transform.header.stamp.sec = sec;
+        transform.child_frame_id = child.into();
+        ((parent.into(), child.into()), KnownTransform { transform, is_static })
+    }
 
-                // hardcoded height
-                gantry_transform.transform.translation.z = 1.93;
+    #[test]
+    fn in_target_frame_follows_chains_in_both_directions() {
+        // map -> base -> rig, and camera -> rig given the other way around
+        let frames: KnownTransforms = vec![
+            known("map", "base", 1.0, 0, true),
+            known("base", "rig", 2.0, 10, false),
+            known("camera", "rig", 0.5, 10, false),
+        ].into_iter().collect();
+        let mut marker = transform(0.25, 0.0, 0.0);
+        marker.header.frame_id = "camera".into();
+        marker.header.stamp.sec = 10;
+        marker.child_frame_id = "aruco_0".into();
 
-                state.gantry_transform = Some(gantry_transform);
-            } else {
-                state.gantry_transform = None;
-            }
+        let in_map = in_target_frame(&marker, "map", &frames, 10_000_000_000, 5_000_000_000)
+            .expect("the chain from map to camera was not found");
+        assert_eq!(in_map.header.frame_id, "map");
+        assert_eq!(in_map.child_frame_id, "aruco_0");
+        assert_eq!(in_map.header.stamp.sec, 10);
+        assert!((in_map.transform.translation.x - 2.75).abs() < 1e-9);
+        assert!(in_target_frame(&marker, "world", &frames, 10_000_000_000, 5_000_000_000)
+                .is_none());
+    }
+
+    #[test]
+    fn in_target_frame_ignores_expired_transforms() {
+        let frames: KnownTransforms = vec![
+            known("map", "base", 1.0, 0, true),
+            known("base", "camera", 2.0, 10, false),
+        ].into_iter().collect();
+        let mut marker = transform(0.0, 0.0, 0.0);
+        marker.header.frame_id = "camera".into();
+        // static transforms never expire, the others after max_age_ns
+        assert!(in_target_frame(&marker, "map", &frames, 15_000_000_000, 5_000_000_000)
+                .is_some());
+        assert!(in_target_frame(&marker, "map", &frames, 15_000_000_001, 5_000_000_000)
+                .is_none());
+        assert!(lookup_transform("map", "base", &frames, 100_000_000_000, 5_000_000_000)
+                .is_some());
+    }
+
+    #[test]
+    fn marker_ok_rejects_tilted_markers() {
+        let upright = transform(0.0, 0.0, 1.0);
+        assert!(marker_ok(&upright, 0.2, 0.9));
+        // turned about the camera axis only, still facing the camera
+        let mut turned = upright.clone();
+        set_msg_quaternion(&mut turned.transform.rotation, Quaternion::from_angle_z(Rad(1.0)));
+        assert!(marker_ok(&turned, 0.2, 0.9));
+        let mut tilted = upright;
+        set_msg_quaternion(&mut tilted.transform.rotation, Quaternion::from_angle_x(Rad(0.5)));
+        assert!(!marker_ok(&tilted, 0.2, 0.9));
+    }
+
+    #[test]
+    fn markers_tilted_by_45_degrees_are_not_used() {
+        let mut tilted = transform(0.0, 0.0, 1.0);
+        set_msg_quaternion(&mut tilted.transform.rotation, Quaternion::from_angle_x(Deg(45.0)));
+        assert!(!marker_ok(&tilted, 0.2, 0.9));
+
+        // neither as a first sample nor as an update of an upright marker
+        let mut feed = MarkerFeed::new();
+        feed.feed(tilted.clone());
+        assert!(feed.markers.is_empty());
+        feed.feed(transform(0.0, 0.0, 1.0));
+        feed.feed(tilted);
+        assert_eq!(feed.markers["aruco_0"].header.stamp.sec, 2);
+        assert!((feed.markers["aruco_0"].transform.rotation.w - 1.0).abs() < 1e-9);
+    }
+
+    fn pair_params(height: Option<f64>) -> PairParams {
+        PairParams {
+            name: "facade".into(),
+            child_frame_id: "facade_aruco".into(),
+            height,
+            midpoint: false,
+            spacing: 0.0,
+            spacing_tolerance: 0.1,
+            spacing_drop: true,
+            disambiguate_yaw: false,
+            marker_yaw_weight: 0.0,
+            yaw_continuity: false,
+            front_yaw: None,
+            flip: false,
+            single_marker_fallback: false,
+            offset: None,
         }
+    }
+
+    #[test]
+    fn rejected_samples_leave_the_history_unchanged() {
+        let ids = ["aruco_0".to_string(), "aruco_1".to_string(), "aruco_2".to_string(),
+                   "aruco_15".to_string(), "aruco_5".to_string()];
+        let params = simulated_marker_params(&ids);
+        let marker_state = Mutex::new(MarkerState::default());
+        let state = Mutex::new(State::default());
+        let frames = Mutex::new(KnownTransforms::new());
+        let mut timings = [LockTiming::new("markers"), LockTiming::new("state")];
+        let marker = |id: &str, x: f64, sec: i32| {
+            let mut t = transform(x, 0.0, 2.0);
+            t.child_frame_id = id.into();
+            t.header.stamp.sec = sec;
+            t
+        };
+        let mut process = |t: TransformStamped| {
+            let now_ns = stamp_to_nanos(&t.header.stamp);
+            process_marker(&params, &marker_state, &state, &frames, &mut timings,
+                           "test".into(), t, now_ns, i64::MAX, "test");
+            lock_state(&state, "test").facade_history.samples.len()
+        };
+        process(marker("aruco_0", 0.0, 1));
+        assert_eq!(process(marker("aruco_1", 1.0, 1)), 1);
+
+        // a jump, a sample older than the current one and a tilted one are rejected
+        assert_eq!(process(marker("aruco_1", 5.0, 2)), 1);
+        assert_eq!(process(marker("aruco_1", 1.0, 0)), 1);
+        let mut tilted = marker("aruco_1", 1.0, 3);
+        set_msg_quaternion(&mut tilted.transform.rotation, Quaternion::from_angle_x(Rad(1.0)));
+        assert_eq!(process(tilted), 1);
 
-        if msg.child_frame_id == "aruco_5" {
-            update_or_set(msg.clone(), &mut state.lock().unwrap().marker_5);
+        assert_eq!(process(marker("aruco_1", 1.0, 4)), 2);
+    }
+
+    #[test]
+    fn marker_throughput_derives_the_transforms() {
+        // the same path as benches/marker_throughput.rs, one second of it
+        let (detections, _) = marker_throughput(60);
+        assert_eq!(detections, 300);
+    }
+
+    #[test]
+    fn pair_transform_along_x_is_upside_down_with_zero_yaw() {
+        let first = transform(0.0, 0.0, 2.0);
+        let second = transform(1.0, 0.0, 2.0);
+        let yaw = pair_yaw(&first, &second).unwrap();
+        let t = pair_transform(&first, &second, "facade_aruco", false, yaw);
+        assert_eq!(t.child_frame_id, "facade_aruco");
+        assert_eq!(translation(&t), (1.0, 0.0, 2.0));
+        assert!(transform_yaw(&t).abs() < 1e-12);
+        // a half turn about x: x stays, y and z are flipped
+        let q = quaternion_from_msg(&t.transform.rotation);
+        assert!((q.v.x.abs() - 1.0).abs() < 1e-12);
+        let z = q * Vector3::unit_z();
+        assert!((z.z + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn pair_transform_along_y_has_a_quarter_turn_yaw() {
+        let first = transform(0.0, 0.0, 1.0);
+        let second = transform(0.0, 1.0, 1.0);
+        let yaw = pair_yaw(&first, &second).unwrap();
+        let t = pair_transform(&first, &second, "gantry_aruco", true, yaw);
+        let (x, y, z) = translation(&t);
+        assert!(x.abs() < 1e-12 && (y - 0.5).abs() < 1e-12 && (z - 1.0).abs() < 1e-12);
+        assert!((transform_yaw(&t) - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+        let z_axis = quaternion_from_msg(&t.transform.rotation) * Vector3::unit_z();
+        assert!((z_axis.z + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn update_pair_transform_keeps_the_yaw_from_flipping() {
+        let mut params = pair_params(None);
+        params.yaw_continuity = true;
+        let (a, b) = (transform(0.0, 0.0, 2.0), transform(1.0, 0.0, 2.0));
+        let mut derived = None;
+        let mut single_offset = None;
+        let mut throttle = LogThrottle::default();
+        update_pair_transform([Some(&a), Some(&b)], &[], &mut derived, &mut single_offset,
+                              &params, &mut throttle, "test");
+        assert!(transform_yaw(derived.as_ref().unwrap()).abs() < 1e-12);
+        // the markers are seen the other way around, the yaw stays
+        update_pair_transform([Some(&b), Some(&a)], &[], &mut derived, &mut single_offset,
+                              &params, &mut throttle, "test");
+        assert!(transform_yaw(derived.as_ref().unwrap()).abs() < 1e-12);
+        // without a previous transform the front direction decides
+        params.front_yaw = Some(std::f64::consts::PI);
+        let mut derived = None;
+        update_pair_transform([Some(&a), Some(&b)], &[], &mut derived, &mut single_offset,
+                              &params, &mut throttle, "test");
+        let yaw = transform_yaw(derived.as_ref().unwrap());
+        assert!((yaw.abs() - std::f64::consts::PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn update_pair_transform_applies_the_height_and_drops_missing_pairs() {
+        let first = transform(0.0, 0.0, 2.0);
+        let second = transform(1.0, 1.0, 2.0);
+        let mut derived = None;
+        let mut single_offset = None;
+        update_pair_transform([Some(&first), Some(&second)], &[], &mut derived, &mut single_offset,
+                              &pair_params(Some(3.57)), &mut LogThrottle::default(), "test");
+        let t = derived.expect("no transform derived");
+        assert_eq!(translation(&t), (1.0, 1.0, 3.57));
+        assert!((transform_yaw(&t) - std::f64::consts::FRAC_PI_4).abs() < 1e-12);
+        // without the fallback a missing marker drops the transform
+        let mut derived = Some(t);
+        update_pair_transform([Some(&first), None], &[], &mut derived, &mut single_offset,
+                              &pair_params(None), &mut LogThrottle::default(), "test");
+        assert!(derived.is_none());
+    }
+
+    #[test]
+    fn single_marker_fallback_keeps_the_height_with_the_offset() {
+        let mut params = pair_params(Some(3.57));
+        params.single_marker_fallback = true;
+        let mut offset = transform(0.1, 0.0, 0.2);
+        set_msg_quaternion(&mut offset.transform.rotation, Quaternion::from_angle_x(Rad(0.3)));
+        params.offset = Some(offset);
+        let first = transform(0.0, 0.0, 2.0);
+        let second = transform(1.0, 0.0, 2.0);
+        let mut derived = None;
+        let mut single_offset = None;
+        update_pair_transform([Some(&first), Some(&second)], &[], &mut derived, &mut single_offset,
+                              &params, &mut LogThrottle::default(), "test");
+        let paired = derived.clone().expect("no transform derived");
+        assert!((paired.transform.translation.z - 3.57).abs() > 0.1);
+
+        // the first marker is lost, then the remaining one moves, also in z. the
+        // derived frame follows it in the plane only.
+        let moved = transform(1.5, 0.0, 2.3);
+        for marker in [&second, &moved].iter() {
+            update_pair_transform([None, Some(marker)], &[], &mut derived, &mut single_offset,
+                                  &params, &mut LogThrottle::default(), "test");
         }
+        let single = derived.expect("the fallback dropped the transform");
+        assert!((single.transform.translation.x - paired.transform.translation.x - 0.5).abs()
+                < 1e-9);
+        assert!((single.transform.translation.z - paired.transform.translation.z).abs() < 1e-9);
+    }
 
-        {
-            let mut state = state.lock().unwrap();
-            if state.marker_5.is_some() {
-                let mut agv_transform = state.marker_5.as_ref().unwrap().clone();
-                agv_transform.transform.translation.z = 3.27;
-                agv_transform.child_frame_id = "agv_aruco".into();
-                state.agv_transform = Some(agv_transform);
-            }
+    #[test]
+    fn line_yaw_fits_all_markers_and_keeps_the_direction() {
+        let markers = [transform(0.0, 0.0, 0.0), transform(1.0, 0.1, 0.0),
+                       transform(2.0, 0.1, 0.0), transform(3.0, 0.0, 0.0)];
+        let refs: Vec<&TransformStamped> = markers.iter().collect();
+        assert!(line_yaw(&refs, 0.1).abs() < 1e-12);
+        assert!((line_yaw(&refs, 3.0).abs() - std::f64::consts::PI).abs() < 1e-12);
+        // a vertical line is fine too
+        let markers = [transform(0.0, 0.0, 0.0), transform(0.0, 1.0, 0.0),
+                       transform(0.0, 2.0, 0.0)];
+        let refs: Vec<&TransformStamped> = markers.iter().collect();
+        assert!((line_yaw(&refs, 1.5) - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn blend_yaw_flips_ambiguous_heading() {
+        // the markers swapped apparent order, so the positions point the wrong way
+        let first = transform(1.0, 0.0, 0.0);
+        let second = transform(0.0, 0.0, 0.0);
+        let position_yaw = pair_yaw(&first, &second).unwrap();
+        let measured_yaw = circular_mean(transform_yaw(&first), transform_yaw(&second));
+        assert!((position_yaw.abs() - std::f64::consts::PI).abs() < 1e-12);
+        assert!((blend_yaw(position_yaw, measured_yaw, true, 0.0)).abs() < 1e-12);
+        assert!((blend_yaw(position_yaw, measured_yaw, false, 0.0).abs() -
+                 std::f64::consts::PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn blend_yaw_moves_towards_measured() {
+        assert!((blend_yaw(0.2, 0.0, true, 0.5) - 0.1).abs() < 1e-12);
+        assert!((blend_yaw(0.2, 0.0, true, 0.0) - 0.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn filter_transform_moves_by_fraction_of_difference() {
+        let old = transform(1.0, 2.0, 3.0);
+        let new = transform(5.0, -2.0, 4.0);
+        let (x, y, z) = translation(&filter_transform(new, &old, &uniform(4.0)));
+        assert!((x - 2.0).abs() < 1e-12);
+        assert!((y - 1.0).abs() < 1e-12);
+        assert!((z - 3.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn filter_transform_default_step() {
+        let old = transform(0.0, 0.0, 0.0);
+        let new = transform(1.0, 2.0, -3.0);
+        let (x, y, z) = translation(&filter_transform(new, &old, &uniform(10.0)));
+        assert!((x - 0.1).abs() < 1e-12);
+        assert!((y - 0.2).abs() < 1e-12);
+        assert!((z + 0.3).abs() < 1e-12);
+    }
+
+    #[test]
+    fn filter_transform_converges_to_repeated_sample() {
+        let target = transform(1.0, -1.0, 0.5);
+        let mut estimate = transform(0.0, 0.0, 0.0);
+        for _ in 0..500 {
+            estimate = filter_transform(target.clone(), &estimate, &uniform(10.0));
         }
+        let (x, y, z) = translation(&estimate);
+        assert!((x - 1.0).abs() < 1e-9);
+        assert!((y + 1.0).abs() < 1e-9);
+        assert!((z - 0.5).abs() < 1e-9);
+    }
 
-        future::ready(())
-    }).await;
+    #[test]
+    fn filter_transform_slerps_rotation_along_the_shorter_arc() {
+        let old = transform(0.0, 0.0, 0.0);
+        let mut new = transform(0.0, 0.0, 0.0);
+        set_msg_quaternion(&mut new.transform.rotation,
+                           Quaternion::from_angle_z(Deg(90.0)));
+        let filtered = filter_transform(new.clone(), &old, &uniform(2.0));
+        assert!((transform_yaw(&filtered) - 45f64.to_radians()).abs() < 1e-9);
+        // the same orientation with the opposite sign must give the same result
+        let q = quaternion_from_msg(&new.transform.rotation);
+        set_msg_quaternion(&mut new.transform.rotation, -q);
+        let filtered = filter_transform(new, &old, &uniform(2.0));
+        assert!((transform_yaw(&filtered) - 45f64.to_radians()).abs() < 1e-9);
+        let norm = quaternion_from_msg(&filtered.transform.rotation).magnitude();
+        assert!((norm - 1.0).abs() < 1e-12);
+    }
 
-    handle.await?;
+    #[test]
+    fn time_smoothing_moves_by_dt_over_tau_plus_dt() {
+        let old = transform(0.0, 0.0, 0.0);
+        let new = transform(1.0, 0.0, 0.0);
+        let (x, _, _) = translation(&filter_transform(new.clone(), &old,
+                                                      &time_smoothing(0.3, 0.1)));
+        assert!((x - 0.25).abs() < 1e-12);
+        let (x, _, _) = translation(&filter_transform(new, &old, &time_smoothing(0.3, 0.0)));
+        assert!(x.abs() < 1e-12);
+    }
 
-    Ok(())
+    #[test]
+    fn filter_transform_per_axis_factors() {
+        let old = transform(0.0, 0.0, 0.0);
+        let new = transform(1.0, 1.0, 1.0);
+        let smoothing = Smoothing { translation: [1.0, 2.0, 4.0], rotation: 10.0 };
+        let (x, y, z) = translation(&filter_transform(new, &old, &smoothing));
+        assert!((x - 1.0).abs() < 1e-12);
+        assert!((y - 0.5).abs() < 1e-12);
+        assert!((z - 0.25).abs() < 1e-12);
+    }
 }
@@ -0,0 +1,181 @@
+//! end-to-end tests that run the estimator against synthetic marker detections.
+//! they need a sourced ros environment and are only built with the `ros` feature:
+//!
+//!     cargo test --features ros -- --test-threads=1
+#![cfg(feature = "ros")]
+
+use r2r::geometry_msgs::msg::TransformStamped;
+use r2r::tf2_msgs::msg::TFMessage;
+use r2r::{Context, Node, Publisher};
+use futures::stream::{Stream, StreamExt};
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+const STALE_TIMEOUT_SECS: f64 = 1.0;
+
+/// the estimator running as a separate process, killed when dropped.
+struct Estimator(Child);
+
+impl Estimator {
+    fn start() -> Estimator {
+        Estimator::start_with(&[])
+    }
+
+    /// start with additional `name:=value` parameters.
+    fn start_with(params: &[&str]) -> Estimator {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_gantry_position_estimator"));
+        command.args(["--ros-args", "-p",
+                      &format!("stale_timeout_secs:={}", STALE_TIMEOUT_SECS)]);
+        for param in params {
+            command.args(["-p", param]);
+        }
+        let child = command.spawn().expect("could not start the estimator");
+        Estimator(child)
+    }
+}
+
+impl Drop for Estimator {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// a fake aruco detector publishing a fixed set of markers, and a listener on
+/// the transforms published by the estimator.
+struct Harness {
+    node: Node,
+    clock: r2r::Clock,
+    aruco_pub: Publisher<TransformStamped>,
+    tf_sub: Box<dyn Stream<Item = TFMessage> + Unpin>,
+    // latest transform per child frame id seen on the tf topic
+    seen: HashMap<String, TransformStamped>,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let ctx = Context::create().expect("could not create context");
+        let mut node = Node::create(ctx, "estimator_test", "").expect("could not create node");
+        let aruco_pub = node.create_publisher::<TransformStamped>(
+            "/aruco", r2r::QosProfile::default()).expect("could not create publisher");
+        let tf_sub = node.subscribe::<TFMessage>("/rita/tf", r2r::QosProfile::default())
+            .expect("could not subscribe");
+        let clock = r2r::Clock::create(r2r::ClockType::RosTime).expect("could not create clock");
+        Harness {
+            node,
+            clock,
+            aruco_pub,
+            tf_sub: Box::new(tf_sub),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// spin for `duration`, publishing `markers` at 20 Hz and recording the frames seen on tf.
+    fn run(&mut self, duration: Duration, markers: &[(&str, [f64; 3])]) {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            let now = self.clock.get_now().expect("could not get ros time");
+            for (id, position) in markers {
+                let mut t = TransformStamped::default();
+                t.header.stamp = r2r::Clock::to_builtin_time(&now);
+                t.header.frame_id = "camera".into();
+                t.child_frame_id = id.to_string();
+                t.transform.translation.x = position[0];
+                t.transform.translation.y = position[1];
+                t.transform.translation.z = position[2];
+                t.transform.rotation.w = 1.0;
+                self.aruco_pub.publish(&t).expect("could not publish");
+            }
+
+            let spin_until = Instant::now() + Duration::from_millis(50);
+            while Instant::now() < spin_until {
+                self.node.spin_once(Duration::from_millis(5));
+                while let Some(Some(msg)) = self.tf_sub.next().now_or_never() {
+                    for t in msg.transforms {
+                        self.seen.insert(t.child_frame_id.clone(), t);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn yaw(t: &TransformStamped) -> f64 {
+    let q = &t.transform.rotation;
+    (2.0 * (q.x * q.y + q.w * q.z)).atan2(1.0 - 2.0 * (q.y * q.y + q.z * q.z))
+}
+
+fn assert_yaw(t: &TransformStamped, expected: f64) {
+    let diff = yaw(t) - expected;
+    let diff = diff.sin().atan2(diff.cos());
+    assert!(diff.abs() < 1e-3, "{} has yaw {}, expected {}", t.child_frame_id, yaw(t), expected);
+}
+
+// facade along x, gantry along y and the agv off to the side.
+const MARKERS: [(&str, [f64; 3]); 5] = [
+    ("aruco_0", [0.0, 0.0, 2.0]),
+    ("aruco_1", [1.0, 0.0, 2.0]),
+    ("aruco_2", [0.0, 0.0, 1.0]),
+    ("aruco_15", [0.0, 1.0, 1.0]),
+    ("aruco_5", [2.0, 2.0, 0.5]),
+];
+
+#[test]
+fn frames_follow_the_markers() {
+    let _estimator = Estimator::start();
+    let mut harness = Harness::new();
+
+    harness.run(Duration::from_secs(3), &MARKERS);
+    let facade = harness.seen.get("facade_aruco").expect("facade_aruco was not published");
+    let gantry = harness.seen.get("gantry_aruco").expect("gantry_aruco was not published");
+    let agv = harness.seen.get("agv_aruco").expect("agv_aruco was not published");
+    assert_yaw(facade, 0.0);
+    assert_yaw(gantry, std::f64::consts::FRAC_PI_2);
+    assert_yaw(agv, 0.0);
+    assert!((agv.transform.translation.x - 2.0).abs() < 1e-6);
+    assert!((agv.transform.translation.y - 2.0).abs() < 1e-6);
+}
+
+#[test]
+fn frames_disappear_after_the_stale_timeout() {
+    let _estimator = Estimator::start();
+    let mut harness = Harness::new();
+
+    harness.run(Duration::from_secs(3), &MARKERS);
+    assert!(harness.seen.contains_key("facade_aruco"));
+
+    // let the markers go stale, then check that nothing is published anymore.
+    harness.run(Duration::from_secs_f64(STALE_TIMEOUT_SECS + 1.0), &[]);
+    harness.seen.clear();
+    harness.run(Duration::from_secs(1), &[]);
+    for frame in &["facade_aruco", "gantry_aruco", "agv_aruco"] {
+        assert!(!harness.seen.contains_key(*frame), "{} is still published", frame);
+    }
+}
+
+#[test]
+fn simulated_markers_give_known_yaws() {
+    let _estimator = Estimator::start_with(&["simulate:=true"]);
+    let mut harness = Harness::new();
+
+    harness.run(Duration::from_secs(3), &[]);
+    let facade = harness.seen.get("facade_aruco").expect("facade_aruco was not published");
+    let gantry = harness.seen.get("gantry_aruco").expect("gantry_aruco was not published");
+    assert!(harness.seen.contains_key("agv_aruco"), "agv_aruco was not published");
+    assert_yaw(facade, 0.0);
+    assert_yaw(gantry, std::f64::consts::FRAC_PI_2);
+}
+
+#[test]
+fn heights_follow_the_parameters() {
+    let _estimator = Estimator::start_with(&["facade_height:=4.25", "gantry_height:=0.75"]);
+    let mut harness = Harness::new();
+
+    harness.run(Duration::from_secs(3), &MARKERS);
+    let facade = harness.seen.get("facade_aruco").expect("facade_aruco was not published");
+    let gantry = harness.seen.get("gantry_aruco").expect("gantry_aruco was not published");
+    assert!((facade.transform.translation.z - 4.25).abs() < 1e-6);
+    assert!((gantry.transform.translation.z - 0.75).abs() < 1e-6);
+}